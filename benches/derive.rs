@@ -0,0 +1,39 @@
+//! Attempts per second of the tonlib address derivation and of the precomputed fast path
+//!
+//! Run with `cargo bench --bench derive`.
+
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+use ton_shard_master::wallet::{derive_wallet_address, generate_key_pair, AddressDeriver, WalletKind};
+
+/// Time spent measuring each derivation path
+const BUDGET: Duration = Duration::from_secs(2);
+
+/// Derive addresses with increasing wallet ids for `BUDGET`, returns the attempts per second
+fn measure(mut derive: impl FnMut(i32)) -> f64 {
+    let start = Instant::now();
+    let mut attempts = 0;
+    while start.elapsed() < BUDGET {
+        for _ in 0..100 {
+            derive(attempts);
+            attempts += 1;
+        }
+    }
+    attempts as f64 / start.elapsed().as_secs_f64()
+}
+
+fn main() {
+    let (key_pair, _) = generate_key_pair(None).unwrap();
+    println!("{:<16}{:>16}{:>16}{:>10}", "version", "tonlib/s", "fast/s", "speedup");
+    for version in WalletKind::ALL {
+        let deriver = AddressDeriver::new(version).unwrap();
+        let tonlib = measure(|wallet_id| {
+            black_box(derive_wallet_address(&key_pair, version, 0, wallet_id).unwrap());
+        });
+        let fast = measure(|wallet_id| {
+            black_box(deriver.derive(&key_pair, 0, wallet_id).unwrap());
+        });
+        println!("{:<16}{:>16.0}{:>16.0}{:>9.1}x", version.name(), tonlib, fast, fast / tonlib);
+    }
+}
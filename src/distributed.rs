@@ -0,0 +1,562 @@
+//! Search split across machines: a coordinator hands out index ranges, workers search them
+//!
+//! Only subwallet and split-key searches are distributed: their candidates are numbered, so the
+//! coordinator splits the indices into disjoint ranges, and workers only ever see the public key.
+//! Workers report the indices of their hits, which the coordinator derives again before counting
+//! them, and the first index they did not try, so the rest of a range is handed out again.
+//!
+//! The protocol is one JSON object per line over TCP. A worker sends `{"type":"next"}` and gets a
+//! `job` or `done`, then sends a `report` of the job and gets an `ack` or `done`. While it searches
+//! a job, the worker sends an unanswered `alive` every few seconds. Ranges of workers that
+//! disconnect or stay silent for [`WORKER_TIMEOUT`] before reporting are handed out again.
+
+use std::io::{BufRead, BufReader, ErrorKind, Write};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use std::ops::Range;
+use std::sync::atomic::Ordering;
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use tonlib::mnemonic::KeyPair;
+
+use crate::error::{Error, Result};
+use crate::search::{search_wallets, FoundWallet, SearchConfig, SearchMode, SearchProgress, SearchResult};
+use crate::shard::ShardIdent;
+use crate::splitkey::offset_public_key;
+use crate::wallet::{AddressDeriver, WalletKind};
+
+/// Silence after which the coordinator drops a worker and hands out its range again
+pub const WORKER_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Interval of the `alive` messages of a worker searching a job
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Kind of the candidates of a job
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum JobKind {
+    Subwallet,
+    SplitKey,
+}
+
+/// Range of candidates handed to a worker
+#[derive(Debug, Serialize, Deserialize)]
+struct Job {
+    id: u64,
+    kind: JobKind,
+    /// Public key in hex, the base key of split-key searches
+    public_key: String,
+    wallet_version: String,
+    global_id: i32,
+    workchain: i32,
+    /// Targets still short of wallets, as `workchain:shard` with the number of missing wallets
+    targets: Vec<(String, usize)>,
+    start: u64,
+    end: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum Request {
+    /// Ask for a job
+    Next,
+    /// Result of a job
+    Report {
+        job: u64,
+        /// Indices of the candidates that landed in a target
+        hits: Vec<u64>,
+        attempts: u64,
+        /// First index of the job that was not tried
+        next_index: u64,
+    },
+    /// The worker is still searching its job, not answered
+    Alive,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum Response {
+    Job(Job),
+    Ack,
+    /// Every target got its wallets, or there is nothing left to search
+    Done,
+}
+
+/// Coordinator of a distributed search
+pub struct Coordinator {
+    targets: Vec<(ShardIdent, usize)>,
+    config: SearchConfig,
+    mode: SearchMode,
+    job_size: u64,
+    worker_timeout: Duration,
+}
+
+impl Coordinator {
+    /// Coordinator of a subwallet or split-key search, handing out `job_size` candidates per job
+    ///
+    /// Vanity patterns are not supported, workers only match shards.
+    pub fn new(targets: &[(ShardIdent, usize)], config: &SearchConfig, mode: &SearchMode, job_size: u64) -> Result<Self> {
+        if !matches!(mode, SearchMode::Subwallet { .. } | SearchMode::SplitKey { .. }) {
+            return Err(Error::Distributed("only subwallet and split-key searches can be distributed".to_string()));
+        }
+        if config.pattern.is_some() {
+            return Err(Error::Distributed("vanity patterns cannot be distributed".to_string()));
+        }
+        if let Some((shard, _)) = targets.iter().find(|(shard, _)| shard.workchain() != config.workchain) {
+            return Err(Error::InvalidShard(format!("{} is not in workchain {}", shard, config.workchain)));
+        }
+        Ok(Coordinator { targets: targets.to_vec(), config: config.clone(), mode: mode.clone(), job_size: job_size.max(1), worker_timeout: WORKER_TIMEOUT })
+    }
+
+    /// Serve workers on `listener` until every target got its wallets
+    ///
+    /// The search also ends when every candidate was handed out and reported (the subwallet ids of
+    /// a key are limited), or when `progress` is cancelled. `progress` follows the reports of the
+    /// workers. Workers still searching when the coordinator returns get `done` on their next request
+    /// as long as the process lives.
+    pub fn run(&self, listener: &TcpListener, progress: &SearchProgress) -> Result<SearchResult> {
+        let state = Arc::new(Shared::new(self)?);
+        listener.set_nonblocking(true)?;
+        loop {
+            {
+                let state = state.state.lock().unwrap();
+                progress.attempts.store(state.attempts, Ordering::Relaxed);
+                *progress.remaining.lock().unwrap() = state.remaining.clone();
+            }
+            if state.finished() || progress.is_cancelled() {
+                break;
+            }
+            match listener.accept() {
+                Ok((stream, _)) => {
+                    stream.set_nonblocking(false)?;
+                    // a worker that stalls without disconnecting gives its range back
+                    stream.set_read_timeout(Some(self.worker_timeout))?;
+                    let state = Arc::clone(&state);
+                    thread::spawn(move || state.serve(stream));
+                }
+                Err(err) if err.kind() == ErrorKind::WouldBlock => thread::sleep(Duration::from_millis(50)),
+                Err(err) => return Err(err.into()),
+            }
+        }
+
+        let mut state = state.state.lock().unwrap();
+        state.closed = true;
+        let mut found = std::mem::take(&mut state.found);
+        found.sort_by_key(|wallet| wallet.shard);
+        let interrupted = progress.is_cancelled() && state.remaining.iter().any(|&(_, count)| count > 0);
+        Ok(SearchResult { found, attempts: state.attempts, next_index: state.next_index(), interrupted })
+    }
+}
+
+/// State shared by the connections of a coordinator
+struct Shared {
+    config: SearchConfig,
+    mode: SearchMode,
+    deriver: AddressDeriver,
+    job_size: u64,
+    /// End of the candidate indices
+    limit: u64,
+    state: Mutex<State>,
+}
+
+struct State {
+    /// Start of the candidates never handed out
+    next_start: u64,
+    /// Ranges handed out again
+    pending: Vec<Range<u64>>,
+    /// Jobs handed out and not reported yet
+    outstanding: Vec<(u64, Range<u64>)>,
+    next_job: u64,
+    remaining: Vec<(ShardIdent, usize)>,
+    found: Vec<FoundWallet>,
+    attempts: u64,
+    /// Set when the coordinator returned, workers are told to stop
+    closed: bool,
+}
+
+impl State {
+    /// Every candidate below this index was tried
+    fn next_index(&self) -> u64 {
+        self.pending.iter().map(|range| range.start).chain(self.outstanding.iter().map(|(_, range)| range.start)).fold(self.next_start, u64::min)
+    }
+}
+
+impl Shared {
+    fn new(coordinator: &Coordinator) -> Result<Self> {
+        let limit = match coordinator.mode {
+            SearchMode::Subwallet { .. } => coordinator.config.version.max_subwallet() as u64 + 1,
+            _ => u64::MAX,
+        };
+        Ok(Shared {
+            config: coordinator.config.clone(),
+            mode: coordinator.mode.clone(),
+            deriver: AddressDeriver::new(coordinator.config.version)?,
+            job_size: coordinator.job_size,
+            limit: coordinator.config.end.map_or(limit, |end| end.min(limit)),
+            state: Mutex::new(State {
+                next_start: coordinator.config.start,
+                pending: Vec::new(),
+                outstanding: Vec::new(),
+                next_job: 0,
+                remaining: coordinator.targets.clone(),
+                found: Vec::new(),
+                attempts: 0,
+                closed: false,
+            }),
+        })
+    }
+
+    fn finished(&self) -> bool {
+        let state = self.state.lock().unwrap();
+        let exhausted = state.next_start >= self.limit && state.pending.is_empty() && state.outstanding.is_empty();
+        state.closed || exhausted || state.remaining.iter().all(|&(_, count)| count == 0)
+    }
+
+    /// Answer the requests of one worker until it disconnects
+    fn serve(&self, stream: TcpStream) {
+        let Ok(reader) = stream.try_clone() else { return };
+        let mut writer = stream;
+        let mut current = None;
+        for line in BufReader::new(reader).lines() {
+            let Ok(line) = line else { break };
+            let response = match serde_json::from_str::<Request>(&line) {
+                Ok(Request::Next) => match self.next_job() {
+                    Some(job) => {
+                        current = Some(job.id);
+                        Response::Job(job)
+                    }
+                    None => Response::Done,
+                },
+                Ok(Request::Report { job, hits, attempts, next_index }) => {
+                    current = None;
+                    self.report(job, &hits, attempts, next_index);
+                    if self.finished() {
+                        Response::Done
+                    } else {
+                        Response::Ack
+                    }
+                }
+                Ok(Request::Alive) => continue,
+                Err(_) => break,
+            };
+            if send(&mut writer, &response).is_err() {
+                break;
+            }
+        }
+        if let Some(job) = current {
+            self.requeue(job, None);
+        }
+    }
+
+    /// Next range to search, `None` once the search is over
+    fn next_job(&self) -> Option<Job> {
+        if self.finished() {
+            return None;
+        }
+        let mut state = self.state.lock().unwrap();
+        let range = match state.pending.pop() {
+            Some(range) => range,
+            None if state.next_start < self.limit => {
+                let start = state.next_start;
+                state.next_start = start.saturating_add(self.job_size).min(self.limit);
+                start..state.next_start
+            }
+            // the last ranges are still being searched
+            None => return None,
+        };
+        let id = state.next_job;
+        state.next_job += 1;
+        state.outstanding.push((id, range.clone()));
+        let (kind, public_key) = match &self.mode {
+            SearchMode::Subwallet { key_pair, .. } => (JobKind::Subwallet, hex::encode(&key_pair.public_key)),
+            SearchMode::SplitKey { public_key } => (JobKind::SplitKey, hex::encode(public_key)),
+            _ => unreachable!("checked by Coordinator::new"),
+        };
+        Some(Job {
+            id,
+            kind,
+            public_key,
+            wallet_version: self.config.version.name().to_string(),
+            global_id: self.config.global_id,
+            workchain: self.config.workchain,
+            targets: state.remaining.iter().filter(|(_, count)| *count > 0).map(|(shard, count)| (shard.to_string(), *count)).collect(),
+            start: range.start,
+            end: range.end,
+        })
+    }
+
+    /// Count the verified hits of a job and hand out the part of its range that was not tried
+    fn report(&self, job: u64, hits: &[u64], attempts: u64, next_index: u64) {
+        self.state.lock().unwrap().attempts += attempts;
+        for &index in hits {
+            // a hit is only counted if the coordinator derives the same wallet in a target
+            let Ok(Some(wallet)) = self.verify(index) else { continue };
+            let mut state = self.state.lock().unwrap();
+            if state.found.iter().any(|found| found.index == index) {
+                continue;
+            }
+            if let Some((_, count)) = state.remaining.iter_mut().find(|(shard, count)| *count > 0 && *shard == wallet.shard) {
+                *count -= 1;
+                state.found.push(wallet);
+            }
+        }
+        self.requeue(job, Some(next_index));
+    }
+
+    /// Forget the job, handing out its range again from `next_index`
+    fn requeue(&self, job: u64, next_index: Option<u64>) {
+        let mut state = self.state.lock().unwrap();
+        let Some(position) = state.outstanding.iter().position(|(id, _)| *id == job) else { return };
+        let (_, range) = state.outstanding.remove(position);
+        let start = next_index.map_or(range.start, |next_index| next_index.max(range.start));
+        if start < range.end && !state.closed {
+            state.pending.push(start..range.end);
+        }
+    }
+
+    /// Wallet of the candidate `index` if it lands in a target that is still short of wallets
+    fn verify(&self, index: u64) -> Result<Option<FoundWallet>> {
+        let (key_pair, mnemonic, subwallet, offset) = match &self.mode {
+            SearchMode::Subwallet { key_pair, mnemonic } if index < self.limit => (key_pair.clone(), mnemonic.clone(), index as u32, 0),
+            SearchMode::SplitKey { public_key } => {
+                let public_key = offset_public_key(public_key, index)?;
+                (KeyPair { public_key: public_key.to_vec(), secret_key: Vec::new() }, None, 0, index)
+            }
+            _ => return Ok(None),
+        };
+        let SearchConfig { version, global_id, workchain, .. } = self.config;
+        let wallet_id = version.wallet_id(global_id, workchain, subwallet);
+        let address = self.deriver.derive(&key_pair, workchain, wallet_id)?;
+        let state = self.state.lock().unwrap();
+        let Some(&(shard, _)) = state.remaining.iter().find(|(shard, count)| *count > 0 && shard.contains_address(&address)) else {
+            return Ok(None);
+        };
+        Ok(Some(FoundWallet { address, key_pair, mnemonic, wallet_id, subwallet, offset, shard, index }))
+    }
+}
+
+/// Totals of a worker
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct WorkerStats {
+    /// Number of jobs searched
+    pub jobs: usize,
+    /// Number of wallets tried
+    pub attempts: u64,
+    /// Number of wallets reported to the coordinator
+    pub hits: usize,
+}
+
+/// Search jobs of the coordinator at `coordinator` with `threads` threads until it is done
+///
+/// `on_job` is called with the range and the result of every job. Cancelling `progress` reports the
+/// current job as far as it got and stops. A coordinator closing the connection ends the worker too.
+pub fn run_worker(coordinator: impl ToSocketAddrs, threads: usize, progress: &SearchProgress, mut on_job: impl FnMut(&Range<u64>, &SearchResult)) -> Result<WorkerStats> {
+    let stream = TcpStream::connect(coordinator)?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = stream;
+    let mut stats = WorkerStats::default();
+    let mut request = Request::Next;
+    loop {
+        send(&mut writer, &request)?;
+        let job = match receive(&mut reader)? {
+            None | Some(Response::Done) => return Ok(stats),
+            Some(Response::Ack) => {
+                request = Request::Next;
+                continue;
+            }
+            Some(Response::Job(job)) => job,
+        };
+        if progress.is_cancelled() {
+            return Ok(stats);
+        }
+
+        let targets = job.targets.iter().map(|(shard, count)| Ok((shard.parse::<ShardIdent>()?, *count))).collect::<Result<Vec<(ShardIdent, usize)>>>()?;
+        let version = job.wallet_version.parse::<WalletKind>().map_err(Error::Distributed)?;
+        let public_key: [u8; 32] = hex::decode(&job.public_key)
+            .ok()
+            .and_then(|key| key.try_into().ok())
+            .ok_or_else(|| Error::InvalidKey(format!("{:?} is not a 32 byte hex key", job.public_key)))?;
+        let mode = match job.kind {
+            JobKind::Subwallet => SearchMode::Subwallet { key_pair: KeyPair { public_key: public_key.to_vec(), secret_key: Vec::new() }, mnemonic: None },
+            JobKind::SplitKey => SearchMode::SplitKey { public_key },
+        };
+        let config = SearchConfig { threads, start: job.start, end: Some(job.end), ..SearchConfig::new(version, job.global_id, job.workchain) };
+        let mut heartbeat = writer.try_clone()?;
+        // the heartbeat stops when `stop` is dropped
+        let (stop, stopped) = mpsc::channel::<()>();
+        let result = thread::scope(|scope| {
+            scope.spawn(move || {
+                while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(HEARTBEAT_INTERVAL) {
+                    if send(&mut heartbeat, &Request::Alive).is_err() {
+                        break;
+                    }
+                }
+            });
+            let result = search_wallets(&[], &targets, &config, &mode, progress, None);
+            drop(stop);
+            result
+        })?;
+
+        let range = job.start..job.end;
+        on_job(&range, &result);
+        stats.jobs += 1;
+        stats.attempts += result.attempts;
+        stats.hits += result.found.len();
+        request = Request::Report {
+            job: job.id,
+            hits: result.found.iter().map(|wallet| wallet.index).collect(),
+            attempts: result.attempts,
+            next_index: result.next_index.min(job.end),
+        };
+        if result.interrupted {
+            send(&mut writer, &request)?;
+            return Ok(stats);
+        }
+    }
+}
+
+fn send<T: Serialize>(writer: &mut TcpStream, message: &T) -> Result<()> {
+    let mut line = serde_json::to_vec(message)?;
+    line.push(b'\n');
+    writer.write_all(&line)?;
+    Ok(())
+}
+
+/// Next message of the connection, `None` if it was closed
+fn receive<T: DeserializeOwned>(reader: &mut BufReader<TcpStream>) -> Result<Option<T>> {
+    let mut line = String::new();
+    match reader.read_line(&mut line) {
+        Ok(0) => Ok(None),
+        Ok(_) => Ok(Some(serde_json::from_str(&line)?)),
+        Err(err) if matches!(err.kind(), ErrorKind::ConnectionReset | ErrorKind::ConnectionAborted) => Ok(None),
+        Err(err) => Err(err.into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::TESTNET_GLOBAL_ID;
+    use crate::shard::shards_for_split_depth;
+    use crate::splitkey::BaseKey;
+    use crate::wallet::{derive_wallet_address, generate_key_pair};
+
+    /// Run `coordinator` against two local workers
+    fn run_locally(coordinator: &Coordinator) -> (SearchResult, Vec<WorkerStats>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::scope(|scope| {
+            let workers: Vec<_> = (0..2).map(|_| scope.spawn(move || run_worker(addr, 2, &SearchProgress::default(), |_, _| {}).unwrap())).collect();
+            let result = coordinator.run(&listener, &SearchProgress::default()).unwrap();
+            drop(listener);
+            (result, workers.into_iter().map(|worker| worker.join().unwrap()).collect())
+        })
+    }
+
+    fn config(version: WalletKind) -> SearchConfig {
+        SearchConfig::new(version, TESTNET_GLOBAL_ID, 0)
+    }
+
+    #[test]
+    fn workers_fill_every_target_of_a_subwallet_search() {
+        let net_shards = shards_for_split_depth(0, 2).unwrap();
+        let (key_pair, mnemonic) = generate_key_pair(None).unwrap();
+        let mode = SearchMode::Subwallet { key_pair: key_pair.clone(), mnemonic: Some(mnemonic) };
+        let targets: Vec<(ShardIdent, usize)> = net_shards.iter().map(|&shard| (shard, 2)).collect();
+        let coordinator = Coordinator::new(&targets, &config(WalletKind::V4R2), &mode, 4).unwrap();
+
+        let (result, workers) = run_locally(&coordinator);
+        assert_eq!(result.found.len(), 8);
+        assert!(!result.interrupted);
+        assert_eq!(workers.iter().map(|worker| worker.attempts).sum::<u64>(), result.attempts);
+        for wallet in &result.found {
+            assert_eq!(wallet.subwallet as u64, wallet.index);
+            assert!(wallet.shard.contains_address(&wallet.address));
+            assert_eq!(wallet.key_pair.secret_key, key_pair.secret_key);
+            let wallet_id = WalletKind::V4R2.wallet_id(TESTNET_GLOBAL_ID, 0, wallet.subwallet);
+            assert_eq!(derive_wallet_address(&key_pair, WalletKind::V4R2, 0, wallet_id).unwrap(), wallet.address);
+        }
+        let mut indices: Vec<u64> = result.found.iter().map(|wallet| wallet.index).collect();
+        indices.sort();
+        indices.dedup();
+        assert_eq!(indices.len(), 8);
+    }
+
+    #[test]
+    fn workers_report_split_key_offsets() {
+        let base = BaseKey::generate();
+        let mode = SearchMode::SplitKey { public_key: base.public_key() };
+        let target = ShardIdent::from_prefix(0, 0b101 << 61, 3).unwrap();
+        let coordinator = Coordinator::new(&[(target, 3)], &config(WalletKind::V5R1), &mode, 8).unwrap();
+
+        let (result, _) = run_locally(&coordinator);
+        assert_eq!(result.found.len(), 3);
+        for wallet in &result.found {
+            assert_eq!(wallet.offset, wallet.index);
+            assert!(target.contains_address(&wallet.address));
+            assert_eq!(wallet.key_pair.public_key, base.combine(wallet.offset).unwrap().public_key().to_vec());
+        }
+    }
+
+    #[test]
+    fn unreported_ranges_are_handed_out_again() {
+        let (key_pair, _) = generate_key_pair(None).unwrap();
+        let mode = SearchMode::Subwallet { key_pair, mnemonic: None };
+        let coordinator = Coordinator::new(&[(ShardIdent::full(0), 1)], &config(WalletKind::V3R2), &mode, 10).unwrap();
+        let shared = Shared::new(&coordinator).unwrap();
+
+        let first = shared.next_job().unwrap();
+        let second = shared.next_job().unwrap();
+        assert_eq!((first.start, first.end, second.start, second.end), (0, 10, 10, 20));
+        shared.requeue(first.id, None);
+        shared.report(second.id, &[], 3, 13);
+        assert_eq!(shared.state.lock().unwrap().next_index(), 0);
+
+        let retried: Vec<(u64, u64)> = (0..2).map(|_| shared.next_job().unwrap()).map(|job| (job.start, job.end)).collect();
+        assert!(retried.contains(&(0, 10)) && retried.contains(&(13, 20)));
+        assert_eq!(shared.next_job().unwrap().start, 20);
+        assert_eq!(shared.state.lock().unwrap().attempts, 3);
+    }
+
+    #[test]
+    fn silent_workers_give_their_range_back() {
+        let (key_pair, _) = generate_key_pair(None).unwrap();
+        let mode = SearchMode::Subwallet { key_pair, mnemonic: None };
+        let target = ShardIdent::from_prefix(0, 0, 2).unwrap();
+        let mut coordinator = Coordinator::new(&[(target, 1)], &config(WalletKind::V4R2), &mode, 1000).unwrap();
+        coordinator.worker_timeout = Duration::from_millis(200);
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        thread::scope(|scope| {
+            let run = scope.spawn(|| coordinator.run(&listener, &SearchProgress::default()).unwrap());
+            // takes the first range and stalls without disconnecting
+            let silent = TcpStream::connect(addr).unwrap();
+            let mut reader = BufReader::new(silent.try_clone().unwrap());
+            send(&mut silent.try_clone().unwrap(), &Request::Next).unwrap();
+            let Some(Response::Job(job)) = receive(&mut reader).unwrap() else { panic!("no job") };
+            assert_eq!((job.start, job.end), (0, 1000));
+            // the coordinator drops the silent worker
+            assert!(receive::<Response>(&mut reader).unwrap().is_none());
+
+            let mut ranges = Vec::new();
+            run_worker(addr, 1, &SearchProgress::default(), |range, _| ranges.push(range.clone())).unwrap();
+            assert_eq!(ranges[0], 0..1000);
+            assert_eq!(run.join().unwrap().found.len(), 1);
+        });
+    }
+
+    #[test]
+    fn only_numbered_searches_are_distributed() {
+        let targets = [(ShardIdent::full(0), 1)];
+        assert!(matches!(Coordinator::new(&targets, &config(WalletKind::V4R2), &SearchMode::RawKey, 10), Err(Error::Distributed(_))));
+        let mut vanity = config(WalletKind::V4R2);
+        vanity.pattern = Some(crate::vanity::VanityPattern::prefix("EQA").unwrap());
+        let mode = SearchMode::SplitKey { public_key: BaseKey::generate().public_key() };
+        assert!(matches!(Coordinator::new(&targets, &vanity, &mode, 10), Err(Error::Distributed(_))));
+    }
+}
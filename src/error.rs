@@ -0,0 +1,58 @@
+//! Error type of the library
+
+use tonlib::address::TonAddressParseError;
+use tonlib::cell::TonCellError;
+use tonlib::client::TonClientError;
+use tonlib::mnemonic::MnemonicError;
+
+/// Errors returned by the library
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    /// A shard id could not be parsed
+    #[error("invalid shard: {0:?}")]
+    InvalidShard(String),
+    /// A shard is not part of the shard layout in use
+    #[error("invalid shard {shard}, choose from: {available}")]
+    UnknownShard {
+        /// The rejected shard, as `workchain:shard`
+        shard: String,
+        /// Comma separated list of the shards in the layout
+        available: String,
+    },
+    /// A vanity pattern is not valid
+    #[error("invalid pattern: {0}")]
+    InvalidPattern(String),
+    /// A public or secret key is not valid
+    #[error("invalid key: {0}")]
+    InvalidKey(String),
+    /// An address could not be parsed
+    #[error("invalid address: {0}")]
+    Address(#[from] TonAddressParseError),
+    /// A wallet cell could not be built
+    #[error("cell error: {0}")]
+    Cell(#[from] TonCellError),
+    /// A mnemonic is not valid or could not be turned into a key pair
+    #[error("mnemonic error: {0}")]
+    Mnemonic(#[from] MnemonicError),
+    /// The liteservers could not be reached or returned an error
+    #[error("network error: {0}")]
+    Network(#[from] TonClientError),
+    /// A global config could not be downloaded
+    #[error("http error: {0}")]
+    Http(#[from] reqwest::Error),
+    /// A distributed search failed or was configured wrongly
+    #[error("distributed search: {0}")]
+    Distributed(String),
+    /// A keystore could not be sealed or opened
+    #[error("keystore error: {0}")]
+    Keystore(String),
+    /// A file could not be read or written
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    /// JSON could not be (de)serialized
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
+}
+
+/// Result type of the library
+pub type Result<T> = std::result::Result<T, Error>;
@@ -0,0 +1,193 @@
+//! Password protected storage for generated wallets
+
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use chacha20poly1305::aead::{Aead, KeyInit};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+
+use crate::error::{Error, Result};
+
+/// Current keystore file format version
+const KEYSTORE_VERSION: u32 = 1;
+
+/// scrypt cost used for new keystores (N = 2^15, ~32 MiB of memory)
+const SCRYPT_LOG_N: u8 = 15;
+const SCRYPT_R: u32 = 8;
+const SCRYPT_P: u32 = 1;
+
+/// Password protected container for generated secrets
+///
+/// The key is derived from the password with scrypt and the payload is sealed with
+/// XChaCha20-Poly1305, so a wrong password or a modified file is rejected on decryption.
+#[derive(Serialize, Deserialize)]
+pub struct Keystore {
+    /// File format version
+    pub version: u32,
+    /// Key derivation parameters
+    pub kdf: KdfParams,
+    /// Cipher name, always `xchacha20poly1305`
+    pub cipher: String,
+    /// Hex encoded 24-byte nonce
+    pub nonce: String,
+    /// Hex encoded ciphertext including the authentication tag
+    pub ciphertext: String,
+}
+
+/// scrypt parameters stored alongside the ciphertext
+#[derive(Serialize, Deserialize)]
+pub struct KdfParams {
+    /// KDF name, always `scrypt`
+    pub name: String,
+    /// log2 of the scrypt cost N
+    pub log_n: u8,
+    /// scrypt block size
+    pub r: u32,
+    /// scrypt parallelism
+    pub p: u32,
+    /// Hex encoded salt
+    pub salt: String,
+}
+
+impl Keystore {
+    /// Encrypt `plaintext` with a key derived from `password`
+    pub fn seal(plaintext: &[u8], password: &str) -> Result<Keystore> {
+        Self::seal_with_cost(plaintext, password, SCRYPT_LOG_N)
+    }
+
+    fn seal_with_cost(plaintext: &[u8], password: &str, log_n: u8) -> Result<Keystore> {
+        let mut salt = [0u8; 32];
+        let mut nonce = [0u8; 24];
+        rand::thread_rng().fill_bytes(&mut salt);
+        rand::thread_rng().fill_bytes(&mut nonce);
+
+        let kdf = KdfParams {
+            name: "scrypt".to_string(),
+            log_n,
+            r: SCRYPT_R,
+            p: SCRYPT_P,
+            salt: hex::encode(salt),
+        };
+        let cipher = XChaCha20Poly1305::new(&kdf.derive_key(password)?.into());
+        let ciphertext = cipher
+            .encrypt(XNonce::from_slice(&nonce), plaintext)
+            .map_err(|_| Error::Keystore("encryption failed".to_string()))?;
+
+        Ok(Keystore {
+            version: KEYSTORE_VERSION,
+            kdf,
+            cipher: "xchacha20poly1305".to_string(),
+            nonce: hex::encode(nonce),
+            ciphertext: hex::encode(ciphertext),
+        })
+    }
+
+    /// Decrypt the payload, failing on a wrong password or a tampered file
+    pub fn open(&self, password: &str) -> Result<Vec<u8>> {
+        if self.version != KEYSTORE_VERSION {
+            return Err(Error::Keystore(format!("unsupported version {}", self.version)));
+        }
+        if self.kdf.name != "scrypt" || self.cipher != "xchacha20poly1305" {
+            return Err(Error::Keystore(format!("unsupported algorithms {}/{}", self.kdf.name, self.cipher)));
+        }
+        let nonce = decode_hex("nonce", &self.nonce)?;
+        if nonce.len() != 24 {
+            return Err(Error::Keystore("invalid nonce".to_string()));
+        }
+        let ciphertext = decode_hex("ciphertext", &self.ciphertext)?;
+
+        let cipher = XChaCha20Poly1305::new(&self.kdf.derive_key(password)?.into());
+        cipher
+            .decrypt(XNonce::from_slice(&nonce), ciphertext.as_slice())
+            .map_err(|_| Error::Keystore("wrong password or corrupted keystore".to_string()))
+    }
+
+    /// Save the keystore as pretty printed JSON, readable by its owner only
+    pub fn write(&self, path: &Path) -> Result<()> {
+        write_private(path, &serde_json::to_vec_pretty(self)?)
+    }
+
+    /// Load a keystore saved with [`Keystore::write`]
+    pub fn read(path: &Path) -> Result<Keystore> {
+        Ok(serde_json::from_str(&std::fs::read_to_string(path)?)?)
+    }
+}
+
+impl KdfParams {
+    fn derive_key(&self, password: &str) -> Result<[u8; 32]> {
+        let params = scrypt::Params::new(self.log_n, self.r, self.p, 32)
+            .map_err(|err| Error::Keystore(format!("invalid scrypt parameters: {}", err)))?;
+        let mut key = [0u8; 32];
+        scrypt::scrypt(password.as_bytes(), &decode_hex("salt", &self.salt)?, &params, &mut key)
+            .map_err(|err| Error::Keystore(format!("key derivation failed: {}", err)))?;
+        Ok(key)
+    }
+}
+
+fn decode_hex(field: &str, value: &str) -> Result<Vec<u8>> {
+    hex::decode(value).map_err(|err| Error::Keystore(format!("invalid {}: {}", field, err)))
+}
+
+/// Write `contents` to `path`, readable by its owner only
+///
+/// The file is written next to `path` and renamed over it, so that an interrupted write keeps the
+/// previous file.
+pub(crate) fn write_private(path: &Path, contents: &[u8]) -> Result<()> {
+    let mut temp = path.as_os_str().to_os_string();
+    temp.push(".tmp");
+    let temp = PathBuf::from(temp);
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let mut file = options.open(&temp)?;
+    // the mode only applies to new files
+    #[cfg(unix)]
+    file.set_permissions(std::os::unix::fs::PermissionsExt::from_mode(0o600))?;
+    file.write_all(contents)?;
+    file.sync_all()?;
+    std::fs::rename(&temp, path)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keystore_roundtrip() {
+        let secret = b"fancy carpet hello mandate penalty trial";
+        let keystore = Keystore::seal_with_cost(secret, "password", 10).unwrap();
+        assert_eq!(keystore.open("password").unwrap(), secret);
+
+        // the keystore survives serialization
+        let json = serde_json::to_string(&keystore).unwrap();
+        let keystore: Keystore = serde_json::from_str(&json).unwrap();
+        assert_eq!(keystore.open("password").unwrap(), secret);
+    }
+
+    #[test]
+    fn keystore_rejects_wrong_password_and_tampering() {
+        let mut keystore = Keystore::seal_with_cost(b"secret", "password", 10).unwrap();
+        assert!(keystore.open("passw0rd").is_err());
+
+        let mut ciphertext = hex::decode(&keystore.ciphertext).unwrap();
+        ciphertext[0] ^= 1;
+        keystore.ciphertext = hex::encode(ciphertext);
+        assert!(keystore.open("password").is_err());
+    }
+
+    #[test]
+    fn keystore_files_are_private() {
+        let path = std::env::temp_dir().join(format!("ton-shard-master-keystore-{}.json", std::process::id()));
+        // a world readable file left at the path is replaced
+        std::fs::write(&path, "{}").unwrap();
+        Keystore::seal_with_cost(b"secret", "password", 10).unwrap().write(&path).unwrap();
+        assert_eq!(Keystore::read(&path).unwrap().open("password").unwrap(), b"secret");
+        #[cfg(unix)]
+        assert_eq!(std::os::unix::fs::PermissionsExt::mode(&std::fs::metadata(&path).unwrap().permissions()) & 0o777, 0o600);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,40 @@
+//! Generate TON wallets that land in a chosen shard
+//!
+//! The crate is split into
+//! - [`shard`]: shard id arithmetic and address to shard lookup
+//! - [`mnemonic`]: native TON mnemonic generation
+//! - [`wallet`]: key generation and wallet address derivation
+//! - [`search`]: the multithreaded search for wallets in given shards
+//! - [`splitkey`]: searches on behalf of someone else's public key, without the secret
+//! - [`distributed`]: a search split between a coordinator and workers on other machines
+//! - [`vanity`]: patterns on the user-friendly address and their difficulty
+//! - [`network`]: embedded network configs and shard discovery through the liteservers
+//! - [`keystore`]: password protected storage for generated wallets
+//!
+//! ```no_run
+//! use ton_shard_master::search::{search_wallets, SearchConfig, SearchMode, SearchProgress};
+//! use ton_shard_master::shard::shards_for_split_depth;
+//! use ton_shard_master::wallet::WalletKind;
+//! use ton_shard_master::network::Network;
+//!
+//! let net_shards = shards_for_split_depth(0, 2)?;
+//! let config = SearchConfig { threads: 4, ..SearchConfig::new(WalletKind::V4R2, Network::Mainnet.global_id(), 0) };
+//! let result = search_wallets(&net_shards, &[(net_shards[0], 1)], &config, &SearchMode::Mnemonic { password: None }, &SearchProgress::default(), None)?;
+//! println!("{}", result.found[0].address);
+//! # Ok::<(), ton_shard_master::Error>(())
+//! ```
+
+#![warn(missing_docs)]
+
+pub mod distributed;
+pub mod error;
+pub mod keystore;
+pub mod mnemonic;
+pub mod network;
+pub mod search;
+pub mod shard;
+pub mod splitkey;
+pub mod vanity;
+pub mod wallet;
+
+pub use error::{Error, Result};
@@ -1,16 +1,39 @@
+use std::collections::BTreeMap;
+use std::io::{BufRead, IsTerminal, Read, Write};
+use std::net::TcpListener;
+use std::path::PathBuf;
+use std::process::ExitCode;
 use std::str::FromStr;
-use clap::{Parser, Subcommand};
-use tonlib::cell::{TonCellError};
-use tonlib::client::{TonClient, TonClientBuilder, TonClientInterface, TonConnectionParams};
-use tonlib::tl::{BlocksShards};
-use tonlib::wallet::{TonWallet, WalletVersion};
-use dialoguer::{theme::ColorfulTheme, Select};
+use std::thread;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use clap::builder::{PossibleValuesParser, TypedValueParser};
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use dialoguer::{theme::ColorfulTheme, Password, Select};
+use serde::{Deserialize, Serialize};
 use inline_colorization::{color_bright_green, color_green, color_red, color_reset, color_yellow};
-use spinners::{Spinner, Spinners};
 use tonlib::address::TonAddress;
-use tonlib::mnemonic::{KeyPair, Mnemonic};
+use tonlib::cell::BagOfCells;
+use tonlib::mnemonic::KeyPair;
+use ton_shard_master::distributed::{run_worker, Coordinator, WorkerStats};
+use ton_shard_master::keystore::Keystore;
+use ton_shard_master::network::{layout_cache_path, load_config, Network, ShardLayout};
+use ton_shard_master::search::{candidate_rng, expected_attempts, search_wallets, Checkpoint, CheckpointWallet, KeyFormat, OnMiss, SearchConfig, SearchMode, SearchParams, SearchProgress, SearchResult};
+use ton_shard_master::shard::{get_shard, parse_prefix, parse_shard, parse_shards, shards_for_split_depth, split_tree, validate_shard, ShardIdent, BASECHAIN};
+use ton_shard_master::vanity::VanityPattern;
+use ton_shard_master::mnemonic::mnemonic_to_key_pair;
+use ton_shard_master::splitkey::{BaseKey, CombinedWallet};
+use ton_shard_master::wallet::{
+    generate_key_pair, generate_key_pair_from, generate_raw_key_pair, generate_raw_key_pair_from, wallets_of_key, KeyWallet, WalletKind,
+};
+
+/// Environment variable holding the keystore password for non-interactive use
+pub const KEYSTORE_PASSWORD_ENV: &str = "TON_SHARD_MASTER_PASSWORD";
+/// Environment variable holding the password of generated mnemonics for non-interactive use
+pub const MNEMONIC_PASSWORD_ENV: &str = "TON_SHARD_MASTER_MNEMONIC_PASSWORD";
 
-pub const TESTNET_CONFIG: &str = include_str!("../testnet-global.config.json");
 
 /// CLI app for generate a TON account with a specific shard
 #[derive(Parser)]
@@ -18,242 +41,1790 @@ pub const TESTNET_CONFIG: &str = include_str!("../testnet-global.config.json");
 struct Cli {
     #[command(subcommand)]
     command: Commands,
+    /// Work offline with the given shard layout instead of querying the network
+    /// (comma separated `workchain:shard`, bare hex shards belong to the basechain)
+    #[arg(long, global = true, value_delimiter = ',', conflicts_with = "split_depth")]
+    shards: Option<Vec<String>>,
+    /// Work offline assuming the basechain is evenly split to this depth (0 means a single shard),
+    /// next to the unsplit masterchain
+    #[arg(long, global = true, value_parser = clap::value_parser!(u8).range(0..=16))]
+    split_depth: Option<u8>,
+    /// Network to work with, the testnet by default
+    #[arg(long, global = true,
+        value_parser = PossibleValuesParser::new(Network::ALL.map(|network| network.name())).map(|name| name.parse::<Network>().unwrap()))]
+    network: Option<Network>,
+    /// Global config to connect with instead of the embedded one (file path or http(s) url); needs
+    /// `--network` naming the network of the config, whose global id goes into wallet ids
+    #[arg(long, global = true, requires = "network")]
+    config: Option<String>,
+    /// Reuse the cached shard layout of the network for this many seconds
+    #[arg(long, global = true, default_value_t = 3600)]
+    cache_ttl: u64,
+    /// Fetch the shard layout from the network even if the cached one is fresh
+    #[arg(long, global = true)]
+    refresh: bool,
+    /// Output format of the results
+    #[arg(long, global = true, value_enum, default_value_t = OutputFormat::Text)]
+    output: OutputFormat,
+}
+
+impl Cli {
+    /// Network to work with
+    fn network(&self) -> Network {
+        self.network.unwrap_or(Network::Testnet)
+    }
+}
+
+/// Output formats of the results
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+enum OutputFormat {
+    /// Human readable colored text
+    Text,
+    /// A single JSON object on stdout (a list for batches)
+    Json,
+    /// CSV with a header row
+    Csv,
 }
 
 #[derive(Subcommand)]
 enum Commands {
     /// Generate a new wallet with assigned shard
-    Generate {
-        /// Specify the shard to assign to the account (choose from predefined options)
-        #[arg(long)]
-        shard: Option<String>,
-    },
+    Generate(Box<GenerateArgs>),
     /// Detect the shard for a given address
     Shard {
-        /// Address to check the shard
-        address: String,
+        /// Address to check the shard, `-` reads addresses from stdin (one per line)
+        #[arg(required_unless_present = "file", conflicts_with = "file", allow_hyphen_values = true)]
+        address: Option<String>,
+        /// Read addresses to check from a file (one per line)
+        #[arg(long)]
+        file: Option<PathBuf>,
+    },
+    /// Show the shard layout of the network: the top block of every shard and the tree of splits
+    Shards,
+    /// Show the wallets of an existing mnemonic or public key and the shard each of them is in
+    Inspect {
+        /// Public key in hex instead of a mnemonic; the mnemonic is read from stdin or prompted for,
+        /// never passed as an argument
+        #[arg(long)]
+        public_key: Option<String>,
+        /// The mnemonic is protected by a password (prompted for, or taken from the environment)
+        #[arg(long, conflicts_with = "public_key")]
+        mnemonic_password: bool,
+        /// Only show this wallet version instead of every supported one
+        #[arg(long,
+            value_parser = PossibleValuesParser::new(WalletKind::ALL.map(|kind| kind.name())).map(|name| name.parse::<WalletKind>().unwrap()))]
+        wallet_version: Option<WalletKind>,
+        /// Number of subwallet ids to show, starting at the default wallet
+        #[arg(long, default_value_t = 1)]
+        subwallets: u32,
+        /// Workchain of the wallets, -1 for the masterchain
+        #[arg(long, default_value_t = BASECHAIN, allow_negative_numbers = true)]
+        workchain: i32,
+    },
+    /// Outsource the search without handing out the secret key
+    SplitKey {
+        #[command(subcommand)]
+        action: SplitKeyAction,
+    },
+    /// Decrypt a keystore written by `generate --keystore` and show its content
+    #[command(alias = "show")]
+    Decrypt {
+        /// Keystore file to decrypt
+        path: PathBuf,
+    },
+}
+
+/// Options of `generate`, boxed as they outweigh every other command
+#[derive(Args)]
+struct GenerateArgs {
+    /// Specify the shard to assign to the account (choose from predefined options),
+    /// as a hex shard id of `--workchain` or as `workchain:shard`
+    #[arg(long, allow_hyphen_values = true, conflicts_with_all = ["all_shards", "per_shard", "prefix", "near"])]
+    shard: Option<String>,
+    /// Pin the wallet to a binary account prefix (e.g. `0110`), independent of the current shard layout
+    #[arg(long, conflicts_with_all = ["all_shards", "per_shard", "shard_depth", "near"])]
+    prefix: Option<String>,
+    /// Put the wallet into the shard of an existing contract (any address form), e.g. a DEX pool
+    #[arg(long, allow_hyphen_values = true, conflicts_with_all = ["all_shards", "per_shard", "workchain"])]
+    near: Option<String>,
+    /// With `--near`, match this many leading account id bits of the contract instead of its current shard
+    #[arg(long, requires = "near", conflicts_with = "shard_depth", value_parser = clap::value_parser!(u8).range(0..=60))]
+    common_bits: Option<u8>,
+    /// Target the shards of an even split to this depth instead of the current shard layout,
+    /// so the wallets stay in the same shard after future splits
+    #[arg(long, value_parser = clap::value_parser!(u8).range(0..=16))]
+    shard_depth: Option<u8>,
+    /// Workchain to deploy the wallet to, -1 for the masterchain
+    #[arg(long, default_value_t = BASECHAIN, allow_negative_numbers = true)]
+    workchain: i32,
+    /// Number of wallets to generate in the shard
+    #[arg(long, conflicts_with_all = ["all_shards", "per_shard"])]
+    count: Option<usize>,
+    /// Generate wallets in every shard of the network
+    #[arg(long)]
+    all_shards: bool,
+    /// Number of wallets to generate in every shard of the network (implies --all-shards)
+    #[arg(long)]
+    per_shard: Option<usize>,
+    /// Number of worker threads used for the search (defaults to all available cores)
+    #[arg(long)]
+    threads: Option<usize>,
+    /// Wallet contract version to generate
+    #[arg(long, default_value_t = WalletKind::V4R2,
+        value_parser = PossibleValuesParser::new(WalletKind::ALL.map(|kind| kind.name())).map(|name| name.parse::<WalletKind>().unwrap()))]
+    wallet_version: WalletKind,
+    /// Keep a single mnemonic and search over subwallet ids instead of generating new mnemonics
+    #[arg(long)]
+    subwallet: bool,
+    /// Search on behalf of the owner of this base public key (hex, from `split-key new`); only the
+    /// winning offset is printed, the owner combines it with the secret using `split-key combine`
+    #[arg(long, conflicts_with_all = ["subwallet", "key_format", "mnemonic_password", "keystore"])]
+    split_key: Option<String>,
+    /// Secret key format of the generated wallet: a 24 word mnemonic, or a raw 32 byte Ed25519
+    /// private key, much faster to generate
+    #[arg(long, default_value_t = KeyFormat::Mnemonic,
+        value_parser = PossibleValuesParser::new(KeyFormat::ALL.map(|format| format.name())).map(|name| name.parse::<KeyFormat>().unwrap()))]
+    key_format: KeyFormat,
+    /// Protect the generated mnemonic with a password, which is then needed to restore the wallet
+    #[arg(long)]
+    mnemonic_password: bool,
+    /// Write the result to an encrypted keystore file instead of printing the mnemonic or private key
+    #[arg(long)]
+    keystore: Option<PathBuf>,
+    /// Draw the keys from a deterministic generator with this seed, for reproducible runs;
+    /// 64 bits are far too few for real funds
+    #[arg(long, conflicts_with = "split_key")]
+    seed: Option<u64>,
+    /// Save the search state to this file on Ctrl-C, and resume from it when it exists
+    #[arg(long)]
+    checkpoint: Option<PathBuf>,
+    /// Hand out ranges of the subwallet or split-key search to workers connecting to this
+    /// address (e.g. `0.0.0.0:7878`) instead of searching locally
+    #[arg(long, conflicts_with_all = ["checkpoint", "worker"])]
+    coordinator: Option<String>,
+    /// Number of candidates handed to a worker at once
+    #[arg(long, requires = "coordinator", default_value_t = 1 << 20)]
+    job_size: u64,
+    /// Search the ranges handed out by the coordinator at this address; the search itself is
+    /// configured on the coordinator
+    #[arg(long, conflicts_with_all = ["shard", "prefix", "near", "all_shards", "per_shard", "subwallet", "split_key", "seed", "checkpoint", "keystore"])]
+    worker: Option<String>,
+    /// Do not show the progress of the search
+    #[arg(long, conflicts_with = "verbose")]
+    quiet: bool,
+    /// Log every wallet that missed the target instead of showing the progress
+    #[arg(long)]
+    verbose: bool,
+    #[command(flatten)]
+    vanity: VanityArgs,
+}
+
+/// Steps of a split-key search
+#[derive(Subcommand)]
+enum SplitKeyAction {
+    /// Generate a base key pair, keep the secret and hand the public key to `generate --split-key`
+    New,
+    /// Combine the offset found by `generate --split-key` with the base secret, read from stdin or
+    /// prompted for
+    Combine {
+        #[command(flatten)]
+        wallet: CombinedWalletArgs,
+        /// Address reported by the search (any form), checked against the combined key
+        #[arg(long, allow_hyphen_values = true)]
+        address: Option<String>,
+    },
+    /// Sign an external message of the wallet with the combined key, the base secret is read from
+    /// stdin or prompted for
+    Sign {
+        #[command(flatten)]
+        wallet: CombinedWalletArgs,
+        /// Unsigned body of the wallet contract as a bag of cells in base64 or hex, e.g. from
+        /// `TonWallet::create_external_body`
+        #[arg(long)]
+        body: String,
+        /// Add the state init of the wallet, for the message that deploys it
+        #[arg(long)]
+        deploy: bool,
     },
 }
 
+/// Wallet of a split-key search
+#[derive(Args)]
+struct CombinedWalletArgs {
+    /// Offset printed by the search
+    #[arg(long)]
+    offset: u64,
+    /// Wallet contract version the search was run with
+    #[arg(long, default_value_t = WalletKind::V4R2,
+        value_parser = PossibleValuesParser::new(WalletKind::ALL.map(|kind| kind.name())).map(|name| name.parse::<WalletKind>().unwrap()))]
+    wallet_version: WalletKind,
+    /// Workchain the search was run in, -1 for the masterchain
+    #[arg(long, default_value_t = BASECHAIN, allow_negative_numbers = true)]
+    workchain: i32,
+}
 
-/// Validate shard input against predefined options
-fn validate_shard(net_shards: Vec<u64>, shard: u64) -> Result<(), String> {
-    if net_shards.contains(&shard) {
-        Ok(())
-    } else {
-        Err(format!(
-            "Invalid shard. Choose from: {}",
-            net_shards.iter().map(|num| format!("{:x}", num)).collect::<Vec<String>>().join(", ")
-        ))
+impl CombinedWalletArgs {
+    /// Read the base secret and combine it with the offset
+    fn combine(&self, global_id: i32) -> anyhow::Result<CombinedWallet> {
+        let seed = parse_key(&read_secret("Base secret key")?)?;
+        Ok(CombinedWallet::new(&seed, self.offset, self.wallet_version, self.workchain, global_id)?)
     }
 }
 
-/// Generate a new mnemonic
-fn generate_key_pair() -> (KeyPair, String) {
-    let mut bip_mnem;
-    let tonlib_mnem ;
-    loop {
-        bip_mnem = bip39::Mnemonic::generate(24).unwrap();
-        tonlib_mnem = match Mnemonic::from_str(&bip_mnem.to_string(), &None) {
-            Ok(mnem) => {mnem},
-            Err(_) => {
-                continue
-            },
+/// Vanity pattern options of the `generate` command
+#[derive(Args)]
+#[group(skip)]
+struct VanityArgs {
+    /// Require the user-friendly address to start with this text, including the tag (e.g. `EQDton`
+    /// on the mainnet, `kQDton` on the testnet)
+    #[arg(long, group = "vanity")]
+    vanity_prefix: Option<String>,
+    /// Require the user-friendly address to end with this text
+    #[arg(long, group = "vanity")]
+    vanity_suffix: Option<String>,
+    /// Require the user-friendly address to contain this text
+    #[arg(long, group = "vanity")]
+    vanity_contains: Option<String>,
+    /// Require the user-friendly address to match this regular expression
+    #[arg(long, group = "vanity")]
+    vanity_regex: Option<String>,
+    /// Match the vanity pattern ignoring the case of letters
+    #[arg(long, requires = "vanity")]
+    ignore_case: bool,
+    /// Match the vanity pattern against the non-bounceable (`UQ`) form instead of the bounceable one
+    #[arg(long, requires = "vanity")]
+    non_bounceable: bool,
+}
+
+impl VanityArgs {
+    /// The requested pattern, if any, matched against the address form of `network`
+    fn pattern(&self, network: Network) -> ton_shard_master::Result<Option<VanityPattern>> {
+        let pattern = if let Some(prefix) = &self.vanity_prefix {
+            VanityPattern::prefix(prefix)?
+        } else if let Some(suffix) = &self.vanity_suffix {
+            VanityPattern::suffix(suffix)?
+        } else if let Some(pattern) = &self.vanity_contains {
+            VanityPattern::contains(pattern)?
+        } else if let Some(regex) = &self.vanity_regex {
+            VanityPattern::regex(regex)?
+        } else {
+            return Ok(None);
         };
-        break;
+        Ok(Some(pattern.ignore_case(self.ignore_case)?.non_bounceable(self.non_bounceable).testnet(network == Network::Testnet)))
     }
+}
 
-    let kp: KeyPair = tonlib_mnem.to_key_pair().unwrap();
+/// User-friendly forms of an address
+#[derive(Clone, Serialize, Deserialize)]
+struct AddressForms {
+    bounceable: String,
+    non_bounceable: String,
+    bounceable_testnet: String,
+    non_bounceable_testnet: String,
+}
 
-    (kp, bip_mnem.to_string())
+impl From<&TonAddress> for AddressForms {
+    fn from(address: &TonAddress) -> Self {
+        AddressForms {
+            bounceable: address.to_base64_url_flags(false, false),
+            non_bounceable: address.to_base64_url_flags(true, false),
+            bounceable_testnet: address.to_base64_url_flags(false, true),
+            non_bounceable_testnet: address.to_base64_url_flags(true, true),
+        }
+    }
 }
 
-/// Create an account using a mnemonic
-fn export_wallet_from_key_pair(key_pair: KeyPair) -> Result<TonWallet, TonCellError> {
-    // Convert the ton-contract KeyPair to tonlib KeyPair
-    let key_pair = tonlib::mnemonic::KeyPair {
-        public_key: Vec::from(key_pair.public_key),
-        secret_key: Vec::from(key_pair.secret_key),
-    };
-    //let wallet = TonWallet::derive(0, WalletVersion::V4R2, &key_pair, 1);
-    TonWallet::derive_default(WalletVersion::V4R2, &key_pair)
+/// Wallet generated by the `generate` command
+#[derive(Clone, Serialize, Deserialize)]
+struct GenerateOutput {
+    wallet_version: String,
+    /// Workchain of the wallet, missing in keystores written before workchains were supported
+    #[serde(default)]
+    workchain: i32,
+    shard: String,
+    address: String,
+    addresses: AddressForms,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    mnemonic: Option<String>,
+    /// Whether the mnemonic needs a password
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    password_protected: bool,
+    /// Raw Ed25519 private key (seed) in hex, for `--key-format raw`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    private_key: Option<String>,
+    /// The same private key in base64
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    private_key_base64: Option<String>,
+    /// Public key of a split-key search, whose secret is only known to its owner
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    public_key: Option<String>,
+    /// Offset to combine with the base secret, for `--split-key`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    split_key_offset: Option<u64>,
+    /// Keystore holding the mnemonic when it is not printed
+    #[serde(skip_serializing_if = "Option::is_none")]
+    keystore: Option<String>,
+    wallet_id: i32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    subwallet: Option<u32>,
+    /// Totals of the whole run, shared by all wallets of a batch
+    attempts: u64,
+    elapsed_secs: f64,
 }
 
+impl GenerateOutput {
+    fn print_text(&self) {
+        println!("Save this information for later use:");
+        println!("{color_green}Shard is FOUND <:). account_shard: {}:{}{color_reset}", self.workchain, self.shard);
+        println!("Wallet version: {color_yellow}{}{color_reset}", self.wallet_version);
+        println!("Wallet address: {color_yellow}{}{color_reset}", self.addresses.bounceable);
 
-async fn get_shards_from_network() ->  anyhow::Result<(TonClient, Vec<u64>)> {
-    TonClient::set_log_verbosity_level(0);
-    let client = TonClientBuilder::new()
-        .with_pool_size(10)
-        .with_connection_params(&TonConnectionParams{
-            config: TESTNET_CONFIG.to_string(),
-            ..Default::default()
-        })
-        .build()
-        .await?;
-
-    let (_, info) = client.get_masterchain_info().await?;
-    let block_shards: BlocksShards = client.get_block_shards(&info.last).await?;
-    let mut shards = block_shards.shards.clone();
-
-    shards.insert(0, info.last.clone());
-    let mut net_shards = Vec::new();
-    for shard in shards {
-        // skip masterchain shard 8000000000000000
-        if shard.shard as u64 == 9223372036854775808u64 {
-            continue;
+        println!("Wallet address (bounceable, production): {color_yellow}{:?}{color_reset}", self.addresses.bounceable);
+        println!("Wallet address (non bounceable, production): {color_yellow}{:?}{color_reset}", self.addresses.non_bounceable);
+        println!("Wallet address (bounceable, non production): {color_yellow}{:?}{color_reset}", self.addresses.bounceable_testnet);
+        println!("Wallet address (non bounceable, non production): {color_yellow}{:?}{color_reset}", self.addresses.non_bounceable_testnet);
+
+        println!("Wallet address(HEX): {color_yellow}{:?}{color_reset}", self.address);
+        if let Some(mnemonic) = &self.mnemonic {
+            println!("Account mnemonic: {color_bright_green}{:?}{color_reset}", mnemonic);
+        }
+        if let (Some(private_key), Some(private_key_base64)) = (&self.private_key, &self.private_key_base64) {
+            println!("Private key (hex): {color_bright_green}{}{color_reset}", private_key);
+            println!("Private key (base64): {color_bright_green}{}{color_reset}", private_key_base64);
+        }
+        if let (Some(public_key), Some(offset)) = (&self.public_key, self.split_key_offset) {
+            println!("Public key: {color_yellow}{}{color_reset}", public_key);
+            println!("Split-key offset: {color_bright_green}{}{color_reset} (combine it with `split-key combine --offset {}`)", offset, offset);
+        }
+        if self.password_protected {
+            println!("{color_yellow}The mnemonic is protected by a password, the wallet cannot be restored without it{color_reset}");
+        }
+        if let Some(keystore) = &self.keystore {
+            println!("Account secret is saved to the keystore: {color_bright_green}{}{color_reset}", keystore);
+        }
+        if let Some(subwallet) = self.subwallet {
+            println!("Subwallet id: {color_yellow}{}{color_reset} (wallet id: {})", subwallet, self.wallet_id);
+        }
+    }
+
+    const CSV_HEADER: &'static str = "wallet_version,workchain,shard,address,bounceable,non_bounceable,bounceable_testnet,non_bounceable_testnet,mnemonic,password_protected,private_key,private_key_base64,public_key,split_key_offset,keystore,wallet_id,subwallet,attempts,elapsed_secs";
+
+    fn csv_row(&self) -> String {
+        csv_line([
+            self.wallet_version.clone(),
+            self.workchain.to_string(),
+            self.shard.clone(),
+            self.address.clone(),
+            self.addresses.bounceable.clone(),
+            self.addresses.non_bounceable.clone(),
+            self.addresses.bounceable_testnet.clone(),
+            self.addresses.non_bounceable_testnet.clone(),
+            self.mnemonic.clone().unwrap_or_default(),
+            self.password_protected.to_string(),
+            self.private_key.clone().unwrap_or_default(),
+            self.private_key_base64.clone().unwrap_or_default(),
+            self.public_key.clone().unwrap_or_default(),
+            self.split_key_offset.map(|offset| offset.to_string()).unwrap_or_default(),
+            self.keystore.clone().unwrap_or_default(),
+            self.wallet_id.to_string(),
+            self.subwallet.map(|subwallet| subwallet.to_string()).unwrap_or_default(),
+            self.attempts.to_string(),
+            format!("{:.3}", self.elapsed_secs),
+        ])
+    }
+}
+
+/// Key pair of a subwallet search: the one of the checkpoint when resuming, a new one otherwise
+fn subwallet_key(resumed: Option<&Checkpoint<GenerateOutput>>, key_format: KeyFormat, seed: Option<u64>, password: Option<&str>) -> anyhow::Result<(KeyPair, Option<String>)> {
+    if let Some(key) = resumed.map(|resumed| resumed.subwallet_key(password)).transpose()?.flatten() {
+        return Ok(key);
+    }
+    let mut rng = seed.map(|seed| candidate_rng(seed, 0));
+    Ok(match (key_format, rng.as_mut()) {
+        (KeyFormat::Raw, Some(rng)) => (generate_raw_key_pair_from(rng), None),
+        (KeyFormat::Raw, None) => (generate_raw_key_pair(), None),
+        (KeyFormat::Mnemonic, Some(rng)) => generate_key_pair_from(rng, password).map(|(key_pair, mnemonic)| (key_pair, Some(mnemonic)))?,
+        (KeyFormat::Mnemonic, None) => generate_key_pair(password).map(|(key_pair, mnemonic)| (key_pair, Some(mnemonic)))?,
+    })
+}
+
+/// Exit status of a search stopped by Ctrl-C or SIGTERM, after printing the wallets found so far
+const EXIT_INTERRUPTED: u8 = 130;
+
+/// Set on Ctrl-C or SIGTERM while a search runs
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+/// Set while a search turns signals into [`INTERRUPTED`]
+static CATCHING: AtomicBool = AtomicBool::new(false);
+
+/// Turn Ctrl-C and SIGTERM into [`INTERRUPTED`] instead of ending the process, a second signal still ends it
+fn catch_interrupts(catch: bool) {
+    CATCHING.store(catch, Ordering::SeqCst);
+}
+
+/// Handle Ctrl-C and SIGTERM on the runtime: they stop a search that catches them and end the process otherwise
+fn listen_for_interrupts() {
+    tokio::spawn(async {
+        #[cfg(unix)]
+        let mut terminate = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()).ok();
+        loop {
+            let terminated = async {
+                #[cfg(unix)]
+                if let Some(terminate) = &mut terminate {
+                    terminate.recv().await;
+                    return;
+                }
+                std::future::pending::<()>().await
+            };
+            // exit with 128 + the signal number, like the default handlers
+            let status = tokio::select! {
+                _ = tokio::signal::ctrl_c() => 130,
+                _ = terminated => 143,
+            };
+            if !CATCHING.load(Ordering::SeqCst) || INTERRUPTED.swap(true, Ordering::SeqCst) {
+                std::process::exit(status);
+            }
+        }
+    });
+}
+
+/// Listen for the workers of a distributed search
+fn start_coordinator(addr: &str, targets: &[(ShardIdent, usize)], config: &SearchConfig, mode: &SearchMode, job_size: u64) -> anyhow::Result<(Coordinator, TcpListener)> {
+    if !matches!(mode, SearchMode::Subwallet { .. } | SearchMode::SplitKey { .. }) {
+        anyhow::bail!("--coordinator needs --subwallet or --split-key");
+    }
+    let coordinator = Coordinator::new(targets, config, mode, job_size)?;
+    let listener = TcpListener::bind(addr).map_err(|err| anyhow::anyhow!("cannot listen on {}: {}", addr, err))?;
+    Ok((coordinator, listener))
+}
+
+/// Search the jobs of the coordinator at `addr` until it is done or Ctrl-C is pressed
+fn work_for(addr: &str, threads: usize, log: bool) -> anyhow::Result<WorkerStats> {
+    if log {
+        println!("Searching jobs of {} with {} threads", addr, threads);
+    }
+    let progress = SearchProgress::default();
+    let done = AtomicBool::new(false);
+    catch_interrupts(true);
+    let stats = thread::scope(|scope| {
+        scope.spawn(|| {
+            while !done.load(Ordering::Relaxed) {
+                if INTERRUPTED.load(Ordering::Relaxed) {
+                    progress.cancel();
+                }
+                thread::sleep(Duration::from_millis(50));
+            }
+        });
+        let stats = run_worker(addr, threads, &progress, |range, result| {
+            if log {
+                println!("Searched {}..{}: {} attempts, {} wallet(s) found", range.start, range.end, result.attempts, result.found.len());
+            }
+        });
+        done.store(true, Ordering::Relaxed);
+        stats
+    });
+    catch_interrupts(false);
+    Ok(stats?)
+}
+
+/// Print the wallets of a `generate` run, as a JSON list if `list` is set and as a single object otherwise
+fn print_generate(outputs: &[GenerateOutput], format: OutputFormat, list: bool) {
+    match format {
+        OutputFormat::Text => {
+            for (i, output) in outputs.iter().enumerate() {
+                if i > 0 {
+                    println!();
+                }
+                output.print_text();
+            }
+            if let Some(output) = outputs.first() {
+                println!("Elapsed time: {:.3}s", output.elapsed_secs);
+                println!("Attempts: {} ({:.1} attempts/sec)", output.attempts, output.attempts as f64 / output.elapsed_secs);
+            }
+        }
+        OutputFormat::Json => match outputs {
+            [output] if !list => print_json(output),
+            outputs => print_json(&outputs),
+        },
+        OutputFormat::Csv => {
+            println!("{}", GenerateOutput::CSV_HEADER);
+            for output in outputs {
+                println!("{}", output.csv_row());
+            }
         }
-        net_shards.push(shard.shard as u64);
     }
+}
 
-    Ok((client, net_shards))
+/// Result of the `shard` command
+#[derive(Serialize)]
+struct ShardOutput {
+    address: String,
+    workchain: i32,
+    shard: Option<String>,
+    prefix_bits: Option<u32>,
 }
 
+impl ShardOutput {
+    fn print_text(&self) {
+        match &self.shard {
+            Some(shard) => println!("Shard: {}", shard),
+            None => println!("Shard: Not found"),
+        }
+    }
 
-/// Extract the top 64 bits from a 256-bit account ID
-fn extract_top64(account_id: &str) -> Option<u64> {
-    // Remove the `0:` prefix if present and parse the hex string into bytes
-    let hex_part = account_id.trim_start_matches("0:");
-    let bytes = hex::decode(hex_part).ok()?;
+    const CSV_HEADER: &'static str = "address,workchain,shard,prefix_bits";
 
-    // Ensure we have exactly 32 bytes (256 bits)
-    if bytes.len() != 32 {
-        return None;
+    fn csv_row(&self) -> String {
+        csv_line([
+            self.address.clone(),
+            self.workchain.to_string(),
+            self.shard.clone().unwrap_or_default(),
+            self.prefix_bits.map(|bits| bits.to_string()).unwrap_or_default(),
+        ])
     }
+}
+
+/// Shard of the layout shown by `shards`
+#[derive(Serialize)]
+struct ShardEntry {
+    shard: String,
+    workchain: i32,
+    /// Leading account id bits of the shard
+    prefix: String,
+    prefix_len: u8,
+    keyspace_fraction: f64,
+    /// Top block of the shard, unknown for layouts given on the command line
+    seqno: Option<u32>,
+    root_hash: Option<String>,
+    file_hash: Option<String>,
+}
 
-    // Extract the first 8 bytes (top 64 bits) as a u64
-    Some(u64::from_be_bytes(bytes[0..8].try_into().unwrap()))
+/// Shard layout shown by `shards`
+#[derive(Serialize)]
+struct ShardsOutput {
+    /// Masterchain block the layout was read from, unknown for layouts given on the command line
+    masterchain_seqno: Option<u32>,
+    /// Unix time the layout was fetched at
+    fetched_at: Option<u64>,
+    shards: Vec<ShardEntry>,
+    #[serde(skip)]
+    tree: Vec<String>,
 }
 
-/// Get the shard for a given account ID
-fn get_shard(net_shards: &Vec<u64>, account_id: &str) -> Option<u64> {
-    if let Some(top64) = extract_top64(account_id) {
-        for &shard in net_shards {
-            if shard_contains(shard, top64) {
-                return Some(shard);
+impl ShardsOutput {
+    fn new(shards: &[ShardIdent], layout: Option<&ShardLayout>) -> Self {
+        let mut shards = shards.to_vec();
+        shards.sort();
+        let block = |shard: ShardIdent| layout.and_then(|layout| layout.block(shard));
+        let tree = split_tree(&shards, |shard| match block(shard) {
+            Some(block) => format!("{}  block {}", shard, block.seqno),
+            None => shard.to_string(),
+        });
+        ShardsOutput {
+            masterchain_seqno: layout.map(|layout| layout.masterchain_seqno),
+            fetched_at: layout.map(|layout| layout.fetched_at),
+            shards: shards
+                .iter()
+                .map(|&shard| ShardEntry {
+                    shard: shard.to_string(),
+                    workchain: shard.workchain(),
+                    prefix: shard.binary_prefix(),
+                    prefix_len: shard.prefix_len(),
+                    keyspace_fraction: shard.keyspace_fraction(),
+                    seqno: block(shard).map(|block| block.seqno),
+                    root_hash: block(shard).map(|block| block.root_hash.clone()),
+                    file_hash: block(shard).map(|block| block.file_hash.clone()),
+                })
+                .collect(),
+            tree,
+        }
+    }
+
+    fn print_text(&self) {
+        if let (Some(seqno), Some(fetched_at)) = (self.masterchain_seqno, self.fetched_at) {
+            let age = SystemTime::now().duration_since(UNIX_EPOCH + Duration::from_secs(fetched_at)).unwrap_or_default();
+            println!("Masterchain block: {color_yellow}{}{color_reset} (fetched {} ago)", seqno, format_duration(age.as_secs_f64()));
+        }
+        for entry in &self.shards {
+            println!();
+            let prefix = if entry.prefix.is_empty() { "none".to_string() } else { entry.prefix.clone() };
+            println!("Shard {color_yellow}{}{color_reset}: prefix {} ({} bits, 1/{} of the keyspace)", entry.shard, prefix, entry.prefix_len, 1u64 << entry.prefix_len);
+            if let (Some(seqno), Some(root_hash), Some(file_hash)) = (entry.seqno, &entry.root_hash, &entry.file_hash) {
+                println!("  Top block: {}", seqno);
+                println!("  Root hash: {}", root_hash);
+                println!("  File hash: {}", file_hash);
             }
         }
+        println!();
+        println!("Splits:");
+        for line in &self.tree {
+            println!("{}", line);
+        }
+    }
+
+    const CSV_HEADER: &'static str = "shard,workchain,prefix,prefix_len,keyspace_fraction,seqno,root_hash,file_hash";
+
+    fn csv_rows(&self) -> impl Iterator<Item = String> + '_ {
+        self.shards.iter().map(|entry| {
+            csv_line([
+                entry.shard.clone(),
+                entry.workchain.to_string(),
+                entry.prefix.clone(),
+                entry.prefix_len.to_string(),
+                entry.keyspace_fraction.to_string(),
+                entry.seqno.map(|seqno| seqno.to_string()).unwrap_or_default(),
+                entry.root_hash.clone().unwrap_or_default(),
+                entry.file_hash.clone().unwrap_or_default(),
+            ])
+        })
+    }
+}
+
+/// Wallet of an inspected key
+#[derive(Serialize)]
+struct InspectedWallet {
+    wallet_version: String,
+    subwallet: u32,
+    wallet_id: i32,
+    address: String,
+    bounceable: String,
+    non_bounceable: String,
+    /// Current shard as `workchain:shard`, `None` if it is not in the shard layout
+    shard: Option<String>,
+}
+
+/// Result of the `inspect` command
+#[derive(Serialize)]
+struct InspectOutput {
+    public_key: String,
+    wallets: Vec<InspectedWallet>,
+}
+
+impl InspectOutput {
+    fn new(public_key: &[u8], wallets: &[KeyWallet], net_shards: &[ShardIdent]) -> Self {
+        let wallets = wallets
+            .iter()
+            .map(|wallet| InspectedWallet {
+                wallet_version: wallet.version.to_string(),
+                subwallet: wallet.subwallet,
+                wallet_id: wallet.wallet_id,
+                address: wallet.address.to_hex(),
+                bounceable: wallet.address.to_base64_url_flags(false, false),
+                non_bounceable: wallet.address.to_base64_url_flags(true, false),
+                shard: get_shard(net_shards, &wallet.address.to_hex()).map(|shard| shard.to_string()),
+            })
+            .collect();
+        InspectOutput { public_key: hex::encode(public_key), wallets }
+    }
+
+    fn print_text(&self) {
+        println!("Public key: {color_yellow}{}{color_reset}", self.public_key);
+        for wallet in &self.wallets {
+            println!(
+                "{:<14} subwallet {:<5} {color_yellow}{}{color_reset}  shard: {}",
+                wallet.wallet_version,
+                wallet.subwallet,
+                wallet.non_bounceable,
+                wallet.shard.as_deref().unwrap_or("Not found")
+            );
+        }
+    }
+
+    const CSV_HEADER: &'static str = "wallet_version,subwallet,wallet_id,address,bounceable,non_bounceable,shard";
+
+    fn csv_rows(&self) -> impl Iterator<Item = String> + '_ {
+        self.wallets.iter().map(|wallet| {
+            csv_line([
+                wallet.wallet_version.clone(),
+                wallet.subwallet.to_string(),
+                wallet.wallet_id.to_string(),
+                wallet.address.clone(),
+                wallet.bounceable.clone(),
+                wallet.non_bounceable.clone(),
+                wallet.shard.clone().unwrap_or_default(),
+            ])
+        })
+    }
+}
+
+/// Totals of `generate --worker`
+#[derive(Serialize)]
+struct WorkerOutput {
+    jobs: usize,
+    attempts: u64,
+    /// Wallets reported to the coordinator
+    hits: usize,
+    elapsed_secs: f64,
+}
+
+impl WorkerOutput {
+    fn print_text(&self) {
+        println!("Jobs searched: {}", self.jobs);
+        println!("Wallets reported: {color_yellow}{}{color_reset}", self.hits);
+        println!("Elapsed time: {:.3}s", self.elapsed_secs);
+        println!("Attempts: {} ({:.1} attempts/sec)", self.attempts, self.attempts as f64 / self.elapsed_secs);
+    }
+
+    const CSV_HEADER: &'static str = "jobs,attempts,hits,elapsed_secs";
+
+    fn csv_row(&self) -> String {
+        csv_line([self.jobs.to_string(), self.attempts.to_string(), self.hits.to_string(), self.elapsed_secs.to_string()])
+    }
+}
+
+/// Base key pair of `split-key new`
+#[derive(Serialize)]
+struct BaseKeyOutput {
+    /// Handed to the searcher
+    public_key: String,
+    /// Kept by the owner, needed to combine the found offset
+    secret_key: String,
+}
+
+impl BaseKeyOutput {
+    fn print_text(&self) {
+        println!("Public key (give it to the searcher): {color_yellow}{}{color_reset}", self.public_key);
+        println!("Secret key (keep it, needed to combine the offset): {color_bright_green}{}{color_reset}", self.secret_key);
+    }
+
+    const CSV_HEADER: &'static str = "public_key,secret_key";
+
+    fn csv_row(&self) -> String {
+        csv_line([self.public_key.clone(), self.secret_key.clone()])
+    }
+}
+
+/// Wallet key of `split-key combine`
+#[derive(Serialize)]
+struct CombinedKeyOutput {
+    offset: u64,
+    public_key: String,
+    /// Expanded Ed25519 secret key: the secret scalar followed by the hash prefix
+    expanded_secret_key: String,
+    expanded_secret_key_base64: String,
+    wallet_version: String,
+    workchain: i32,
+    wallet_id: i32,
+    address: String,
+    addresses: AddressForms,
+    /// Current shard as `workchain:shard`, `None` if it is not in the shard layout
+    shard: Option<String>,
+}
+
+impl CombinedKeyOutput {
+    fn new(wallet: &CombinedWallet, offset: u64, version: WalletKind, net_shards: &[ShardIdent]) -> Self {
+        let (secret, address) = (wallet.secret(), wallet.address());
+        CombinedKeyOutput {
+            offset,
+            public_key: hex::encode(secret.public_key()),
+            expanded_secret_key: hex::encode(secret.to_bytes()),
+            expanded_secret_key_base64: BASE64.encode(secret.to_bytes()),
+            wallet_version: version.to_string(),
+            workchain: address.workchain,
+            wallet_id: wallet.wallet_id(),
+            address: address.to_hex(),
+            addresses: AddressForms::from(address),
+            shard: get_shard(net_shards, &address.to_hex()).map(|shard| shard.to_string()),
+        }
+    }
+
+    fn print_text(&self) {
+        println!("Wallet version: {color_yellow}{}{color_reset}", self.wallet_version);
+        println!("Wallet address: {color_yellow}{}{color_reset}", self.addresses.bounceable);
+        println!("Wallet address (non bounceable): {color_yellow}{}{color_reset}", self.addresses.non_bounceable);
+        println!("Wallet address(HEX): {color_yellow}{}{color_reset}", self.address);
+        println!("Shard: {}", self.shard.as_deref().unwrap_or("Not found"));
+        println!("Public key: {color_yellow}{}{color_reset}", self.public_key);
+        println!("Expanded secret key (hex): {color_bright_green}{}{color_reset}", self.expanded_secret_key);
+        println!("Expanded secret key (base64): {color_bright_green}{}{color_reset}", self.expanded_secret_key_base64);
+    }
+
+    const CSV_HEADER: &'static str = "offset,public_key,expanded_secret_key,expanded_secret_key_base64,wallet_version,workchain,wallet_id,address,bounceable,non_bounceable,shard";
+
+    fn csv_row(&self) -> String {
+        csv_line([
+            self.offset.to_string(),
+            self.public_key.clone(),
+            self.expanded_secret_key.clone(),
+            self.expanded_secret_key_base64.clone(),
+            self.wallet_version.clone(),
+            self.workchain.to_string(),
+            self.wallet_id.to_string(),
+            self.address.clone(),
+            self.addresses.bounceable.clone(),
+            self.addresses.non_bounceable.clone(),
+            self.shard.clone().unwrap_or_default(),
+        ])
+    }
+}
+
+/// External message of `split-key sign`
+#[derive(Serialize)]
+struct SignedMessageOutput {
+    address: String,
+    /// Signed external message as a bag of cells, ready to be sent to the network
+    message: String,
+    message_hash: String,
+}
+
+impl SignedMessageOutput {
+    fn new(wallet: &CombinedWallet, body: &str, deploy: bool) -> anyhow::Result<Self> {
+        let body = BagOfCells::parse_base64(body.trim()).or_else(|_| BagOfCells::parse_hex(body.trim()))
+            .map_err(|err| anyhow::anyhow!("Invalid message body: {}", err))?;
+        let message = wallet.external_message(body.single_root()?, deploy)?;
+        Ok(SignedMessageOutput {
+            address: wallet.address().to_base64_url(),
+            message_hash: hex::encode(message.cell_hash()),
+            message: BASE64.encode(BagOfCells::from_root(message).serialize(true)?),
+        })
+    }
+
+    fn print_text(&self) {
+        println!("Wallet address: {color_yellow}{}{color_reset}", self.address);
+        println!("Message hash: {}", self.message_hash);
+        println!("Signed message (base64): {color_bright_green}{}{color_reset}", self.message);
+    }
+
+    const CSV_HEADER: &'static str = "address,message,message_hash";
+
+    fn csv_row(&self) -> String {
+        csv_line([self.address.clone(), self.message.clone(), self.message_hash.clone()])
+    }
+}
+
+/// Read a secret piped into stdin, or prompt for it without echoing it
+fn read_secret(prompt: &str) -> anyhow::Result<String> {
+    let mut stdin = std::io::stdin();
+    if stdin.is_terminal() {
+        return Ok(Password::with_theme(&ColorfulTheme::default()).with_prompt(prompt).interact()?);
+    }
+    let mut secret = String::new();
+    stdin.read_to_string(&mut secret)?;
+    Ok(secret.split_whitespace().collect::<Vec<&str>>().join(" "))
+}
+
+/// Parse a 32 byte key given in hex
+fn parse_key(key: &str) -> anyhow::Result<[u8; 32]> {
+    let bytes = hex::decode(key.trim().trim_start_matches("0x"))?;
+    let len = bytes.len();
+    bytes.try_into().map_err(|_| anyhow::anyhow!("key must be 32 bytes, got {}", len))
+}
+
+/// Result of the `shard` command for a list of addresses
+#[derive(Serialize)]
+struct ShardBatchOutput {
+    results: Vec<ShardOutput>,
+    /// Number of addresses per shard
+    histogram: BTreeMap<String, usize>,
+    not_found: usize,
+    invalid: usize,
+}
+
+impl ShardBatchOutput {
+    fn new(net_shards: &[ShardIdent]) -> Self {
+        ShardBatchOutput {
+            results: Vec::new(),
+            histogram: net_shards.iter().map(|shard| (shard.to_string(), 0)).collect(),
+            not_found: 0,
+            invalid: 0,
+        }
+    }
+
+    fn add(&mut self, output: ShardOutput) {
+        match &output.shard {
+            Some(shard) => *self.histogram.entry(format!("{}:{}", output.workchain, shard)).or_default() += 1,
+            None => self.not_found += 1,
+        }
+        self.results.push(output);
+    }
+
+    fn print_text(&self) {
+        for output in &self.results {
+            println!("{}: {}", output.address, output.shard.as_deref().unwrap_or("Not found"));
+        }
+        let total = self.results.len().max(1) as f64;
+        println!();
+        println!("Addresses per shard:");
+        for (shard, count) in &self.histogram {
+            println!("{color_yellow}{}{color_reset}: {} ({:.1}%)", shard, count, *count as f64 * 100.0 / total);
+        }
+        if self.not_found > 0 {
+            println!("Not found: {}", self.not_found);
+        }
+        if self.invalid > 0 {
+            println!("{color_red}Invalid addresses: {}{color_reset}", self.invalid);
+        }
+    }
+}
+
+/// Detect the shard of an address in any form accepted by `TonAddress::from_str`
+fn lookup_shard(net_shards: &[ShardIdent], address: &str) -> Result<ShardOutput, String> {
+    let ton_address = TonAddress::from_str(address).map_err(|err| format!("Invalid address {:?}: {}", address, err))?;
+    let shard = get_shard(net_shards, ton_address.to_hex().as_str());
+    Ok(ShardOutput {
+        address: ton_address.to_hex(),
+        workchain: ton_address.workchain,
+        shard: shard.map(|shard| format!("{:016x}", shard.prefix())),
+        prefix_bits: shard.map(|shard| shard.prefix_len() as u32),
+    })
+}
+
+/// Redraw a progress line on stderr until `done` is set
+fn report_progress(progress: &SearchProgress, done: &AtomicBool, requested: usize, pattern: Option<&VanityPattern>) {
+    let start = Instant::now();
+    let mut last_draw = start;
+    while !done.load(Ordering::Relaxed) {
+        thread::sleep(Duration::from_millis(50));
+        if last_draw.elapsed() < Duration::from_millis(500) {
+            continue;
+        }
+        last_draw = Instant::now();
+        let attempts = progress.attempts();
+        let remaining = progress.remaining();
+        let missing: usize = remaining.iter().map(|(_, count)| count).sum();
+        let rate = attempts as f64 / start.elapsed().as_secs_f64();
+        let eta = match expected_attempts(&remaining, pattern) {
+            _ if attempts == 0 => "estimating".to_string(),
+            Some(expected) => format_duration(expected / rate),
+            None => "unknown".to_string(),
+        };
+        eprint!("\r\x1b[2K{} attempts, {:.1}/s, found {}/{}, ETA {}", attempts, rate, requested - missing, requested, eta);
+        let _ = std::io::stderr().flush();
+    }
+    eprint!("\r\x1b[2K");
+}
+
+/// Human readable duration of `secs` seconds, e.g. `2h 05m`
+fn format_duration(secs: f64) -> String {
+    if !secs.is_finite() {
+        return "never".to_string();
+    }
+    let secs = secs.round() as u64;
+    match secs {
+        0..=59 => format!("{}s", secs),
+        60..=3599 => format!("{}m {:02}s", secs / 60, secs % 60),
+        3600..=86399 => format!("{}h {:02}m", secs / 3600, secs % 3600 / 60),
+        86400..=31_535_999 => format!("{}d {:02}h", secs / 86400, secs % 86400 / 3600),
+        _ => format!("{:.1} years", secs as f64 / 31_536_000.0),
     }
-    None
 }
 
-/// Check if a shard contains the given account prefix
-fn shard_contains(shard: u64, account_prefix: u64) -> bool {
-    let x = shard.trailing_zeros();
-    let mask = (!0u64) << (x + 1);
-    (shard ^ account_prefix) & mask == 0
+/// Print a result in the requested format
+fn print_json<T: Serialize>(output: &T) {
+    println!("{}", serde_json::to_string_pretty(output).unwrap());
+}
+
+/// Join the fields of a CSV row, quoting the ones holding a comma, a quote or a line break
+fn csv_line<I: IntoIterator<Item = String>>(fields: I) -> String {
+    fields
+        .into_iter()
+        .map(|field| match field.contains([',', '"', '\n', '\r']) {
+            true => format!("\"{}\"", field.replace('"', "\"\"")),
+            false => field,
+        })
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+/// Read a password from the environment variable `env` or prompt for it
+fn read_password(env: &str, prompt: &str, confirm: bool) -> anyhow::Result<String> {
+    if let Ok(password) = std::env::var(env) {
+        return Ok(password);
+    }
+    let theme = ColorfulTheme::default();
+    let mut prompt = Password::with_theme(&theme).with_prompt(prompt);
+    if confirm {
+        prompt = prompt.with_confirmation("Repeat password", "Passwords do not match");
+    }
+    Ok(prompt.interact()?)
+}
+
+/// Resolve the shard layout from the command line or from the network
+async fn resolve_net_shards(cli: &Cli) -> anyhow::Result<Vec<ShardIdent>> {
+    if let Some(shards) = &cli.shards {
+        return Ok(parse_shards(shards)?);
+    }
+    if let Some(depth) = cli.split_depth {
+        let mut net_shards = vec![ShardIdent::masterchain()];
+        net_shards.extend(shards_for_split_depth(BASECHAIN, depth)?);
+        return Ok(net_shards);
+    }
+    Ok(resolve_layout(cli).await?.shards())
+}
+
+/// Shard layout of the network: the cached one while it is fresh, the current one otherwise
+///
+/// A layout fetched from the network replaces the cached one. When the network cannot be reached the
+/// cached layout is used whatever its age.
+async fn resolve_layout(cli: &Cli) -> anyhow::Result<ShardLayout> {
+    let cache = layout_cache_path(cli.network(), cli.config.as_deref());
+    let cached = cache.as_deref().and_then(|path| ShardLayout::read(path).ok());
+    if let Some(layout) = cached.as_ref().filter(|layout| !cli.refresh && layout.age() <= Duration::from_secs(cli.cache_ttl)) {
+        return Ok(layout.clone());
+    }
+    let fetched = async {
+        let config = match &cli.config {
+            Some(location) => load_config(location)
+                .await
+                .map_err(|err| anyhow::anyhow!("Failed to load config {}: {}", location, err))?,
+            None => cli.network().config().to_string(),
+        };
+        anyhow::Ok(ShardLayout::fetch(&config).await?)
+    };
+    match (fetched.await, cached) {
+        (Ok(layout), _) => {
+            if let Some(path) = &cache {
+                if let Err(err) = layout.write(path) {
+                    eprintln!("{color_yellow}Failed to cache the shard layout in {}: {}{color_reset}", path.display(), err);
+                }
+            }
+            Ok(layout)
+        }
+        (Err(err), Some(layout)) => {
+            eprintln!(
+                "{color_yellow}{}; using the shard layout of masterchain block {} cached {} ago{color_reset}",
+                err,
+                layout.masterchain_seqno,
+                format_duration(layout.age().as_secs_f64())
+            );
+            Ok(layout)
+        }
+        (Err(err), None) => Err(err),
+    }
 }
 
 #[tokio::main]
-async fn main() {
-    println!("Welcome TON Shard master tool.");
-    println!();
+async fn main() -> ExitCode {
     let cli = Cli::parse();
-    let (_client, net_shards) = get_shards_from_network().await.unwrap();
+    listen_for_interrupts();
+    let text = cli.output == OutputFormat::Text;
+    if text {
+        println!("Welcome TON Shard master tool.");
+        println!();
+    }
+    let net_shards = match &cli.command {
+        // keystores are read, base keys generated and jobs searched without touching the network,
+        // `shards` resolves the whole layout itself
+        Commands::Decrypt { .. } | Commands::SplitKey { action: SplitKeyAction::New | SplitKeyAction::Sign { .. } } | Commands::Shards => Vec::new(),
+        Commands::Generate(args) if args.worker.is_some() => Vec::new(),
+        _ => match resolve_net_shards(&cli).await {
+            Ok(net_shards) => net_shards,
+            Err(err) => {
+                eprintln!("{}", err);
+                return ExitCode::FAILURE;
+            }
+        },
+    };
     let hex_string = net_shards
         .iter()
-        .map(|num| format!("{:x}", num)) // Convert each i64 to hex
-        .collect::<Vec<String>>(); // Collect into a Vec of hex strings ; // Join them with a separator (optional)
-    println!("Network shards are available (hex): {:?}", hex_string.join(", "));
+        .map(|shard| shard.to_string())
+        .collect::<Vec<String>>();
+    if text && !net_shards.is_empty() {
+        println!("Network shards are available (hex): {:?}", hex_string.join(", "));
+    }
 
 
+    let network = cli.network();
     match cli.command {
-        Commands::Generate { shard } => {
-            let start_time = std::time::Instant::now();
-
-            let user_shard = match shard {
-                Some(shard) => shard,
-                None => {
-                    let shard_id = Select::with_theme(&ColorfulTheme::default())
-                        .with_prompt("Choose a shard for the wallet:")
-                        .items(&hex_string)
-                        .interact()
-                        .unwrap();
-                    hex_string[shard_id].clone()
-                },
+        Commands::Generate(args) => {
+            let GenerateArgs { shard, prefix, near, common_bits, shard_depth, workchain, count, all_shards, per_shard, threads, wallet_version, subwallet, split_key, key_format, mnemonic_password, keystore, seed, checkpoint, coordinator, job_size, worker, quiet, verbose, vanity } = *args;
+            let start_time = Instant::now();
+            let threads = threads.unwrap_or_else(|| thread::available_parallelism().map(|n| n.get()).unwrap_or(1));
+            if let Some(worker) = worker {
+                match work_for(&worker, threads, text && !quiet) {
+                    Ok(stats) => {
+                        let output = WorkerOutput { jobs: stats.jobs, attempts: stats.attempts, hits: stats.hits, elapsed_secs: start_time.elapsed().as_secs_f64() };
+                        match cli.output {
+                            OutputFormat::Text => output.print_text(),
+                            OutputFormat::Json => print_json(&output),
+                            OutputFormat::Csv => {
+                                println!("{}", WorkerOutput::CSV_HEADER);
+                                println!("{}", output.csv_row());
+                            }
+                        }
+                    }
+                    Err(err) => {
+                        eprintln!("{color_red}Worker failed: {}{color_reset}", err);
+                        return ExitCode::FAILURE;
+                    }
+                }
+                return ExitCode::SUCCESS;
+            }
+
+            let pattern = match vanity.pattern(network) {
+                Ok(pattern) => pattern,
+                Err(err) => {
+                    eprintln!("{}", err);
+                    return ExitCode::FAILURE;
+                }
             };
 
-            let shard = user_shard.to_lowercase();
-            println!("Assigned Shard (hex): {}", shard);
+            let near = match near.map(|address| TonAddress::from_str(&address).map_err(|err| format!("Invalid address {:?}: {}", address, err))).transpose() {
+                Ok(near) => near,
+                Err(err) => {
+                    eprintln!("{}", err);
+                    return ExitCode::FAILURE;
+                }
+            };
+            let workchain = near.as_ref().map_or(workchain, |near| near.workchain);
 
-            let shard = u64::from_str_radix(&shard, 16).unwrap();
-            if let Err(err) = validate_shard(net_shards.clone(), shard) {
-                eprintln!("{}", err);
-                return;
+            let workchain_shards: Vec<ShardIdent> = match shard_depth.map(|depth| shards_for_split_depth(workchain, depth)).transpose() {
+                Ok(Some(shards)) => shards,
+                Ok(None) => net_shards
+                    .iter()
+                    .copied()
+                    .filter(|shard| shard.workchain() == workchain)
+                    .collect(),
+                Err(err) => {
+                    eprintln!("{}", err);
+                    return ExitCode::FAILURE;
+                }
+            };
+            if workchain_shards.is_empty() && prefix.is_none() && common_bits.is_none() {
+                eprintln!("No shards of workchain {} in the shard layout", workchain);
+                return ExitCode::FAILURE;
             }
 
-            let mut sp = Spinner::new(Spinners::CircleHalves, "".to_string());
+            // asking for a number of wallets gets a list even if it holds a single one
+            let list = count.is_some() || all_shards || per_shard.is_some();
+            let count = count.unwrap_or(1);
+            let mut targets: Vec<(ShardIdent, usize)> = if let Some(prefix) = prefix {
+                let shard = match parse_prefix(workchain, &prefix) {
+                    Ok(shard) => shard,
+                    Err(err) => {
+                        eprintln!("{}", err);
+                        return ExitCode::FAILURE;
+                    }
+                };
+                if text {
+                    println!("Assigned Prefix: {} ({} bits, shard {})", prefix, shard.prefix_len(), shard);
+                }
+                vec![(shard, count)]
+            } else if let Some(near) = near {
+                let shard = match common_bits {
+                    Some(bits) => ShardIdent::of_address(&near, bits).ok(),
+                    None => get_shard(&workchain_shards, &near.to_hex()),
+                };
+                let Some(shard) = shard else {
+                    eprintln!("No shard of the shard layout contains {}", near);
+                    return ExitCode::FAILURE;
+                };
+                if text {
+                    println!("Assigned Shard (hex): {} ({} bits shared with {})", shard, shard.prefix_len(), near);
+                }
+                vec![(shard, count)]
+            } else if all_shards || per_shard.is_some() {
+                let per_shard = per_shard.unwrap_or(1);
+                if text {
+                    println!("Assigned Shards (hex): all of workchain {}, {} wallet(s) per shard", workchain, per_shard);
+                }
+                workchain_shards.iter().map(|&shard| (shard, per_shard)).collect()
+            } else if shard.is_none() && shard_depth.is_none() && pattern.is_some() {
+                // a vanity pattern alone may land anywhere in the workchain
+                vec![(ShardIdent::full(workchain), count)]
+            } else {
+                let user_shard = match shard {
+                    Some(shard) => shard,
+                    None => {
+                        let items: Vec<String> = workchain_shards.iter().map(|shard| shard.to_string()).collect();
+                        let selected = Select::with_theme(&ColorfulTheme::default())
+                            .with_prompt("Choose a shard for the wallet:")
+                            .items(&items)
+                            .interact();
+                        match selected {
+                            Ok(shard_id) => items[shard_id].clone(),
+                            Err(err) => {
+                                eprintln!("{color_red}Failed to choose a shard, pass --shard: {}{color_reset}", err);
+                                return ExitCode::FAILURE;
+                            }
+                        }
+                    },
+                };
 
-            loop {
-                let (key_pair, mnemonic_string) = generate_key_pair();
-                let wallet = export_wallet_from_key_pair(key_pair).unwrap();
+                let shard = match parse_shard(&user_shard.to_lowercase(), workchain) {
+                    Ok(shard) => shard,
+                    Err(err) => {
+                        eprintln!("{}", err);
+                        return ExitCode::FAILURE;
+                    }
+                };
+                if shard.workchain() != workchain {
+                    eprintln!("Shard {} is not in workchain {}, pass --workchain {}", shard, workchain, shard.workchain());
+                    return ExitCode::FAILURE;
+                }
+                if let Err(err) = validate_shard(&workchain_shards, shard) {
+                    eprintln!("{}", err);
+                    return ExitCode::FAILURE;
+                }
+                if text {
+                    println!("Assigned Shard (hex): {}", shard);
+                }
+                vec![(shard, count)]
+            };
+            let requested: usize = targets.iter().map(|(_, count)| count).sum();
 
+            if mnemonic_password && key_format == KeyFormat::Raw {
+                eprintln!("--mnemonic-password needs --key-format mnemonic");
+                return ExitCode::FAILURE;
+            }
+            let password = match mnemonic_password.then(|| read_password(MNEMONIC_PASSWORD_ENV, "Mnemonic password", true)).transpose() {
+                Ok(password) => password.filter(|password| !password.is_empty()),
+                Err(err) => {
+                    eprintln!("{color_red}Failed to read the mnemonic password: {}{color_reset}", err);
+                    return ExitCode::FAILURE;
+                }
+            };
+            let password_protected = password.is_some();
+            // read up front as it also seals the checkpoint of an interrupted search
+            let keystore_password = match keystore.is_some().then(|| read_password(KEYSTORE_PASSWORD_ENV, "Keystore password", true)).transpose() {
+                Ok(keystore_password) => keystore_password,
+                Err(err) => {
+                    eprintln!("{color_red}Failed to read the keystore password: {}{color_reset}", err);
+                    return ExitCode::FAILURE;
+                }
+            };
+            let split_key = match split_key.as_deref().map(parse_key).transpose() {
+                Ok(split_key) => split_key,
+                Err(err) => {
+                    eprintln!("Invalid split-key public key: {}", err);
+                    return ExitCode::FAILURE;
+                }
+            };
 
-                let maby_account_shard =  get_shard(&net_shards, wallet.address.to_hex().as_str());
-                if let Some(account_shard) = maby_account_shard {
-                    if account_shard == shard {
-                        println!("Save this information for later use:");
-                        println!("{color_green}Shard is FOUND <:). account_shard: {:x?}, expected: {:x?}{color_reset}", account_shard, shard);
-                        println!("Wallet address: {color_yellow}{:?}{color_reset}", wallet.address);
+            let params = SearchParams {
+                wallet_version: wallet_version.to_string(),
+                global_id: network.global_id(),
+                workchain,
+                targets: targets.clone(),
+                pattern: pattern.as_ref().map(|pattern| pattern.to_string()),
+                key_format,
+                password_protected,
+                subwallet,
+                split_key: split_key.map(hex::encode),
+                seed,
+            };
+            let resumed = match checkpoint.as_deref().filter(|path| path.exists()).map(|path| Checkpoint::<GenerateOutput>::read(path, keystore_password.as_deref())).transpose() {
+                Ok(resumed) => resumed,
+                Err(err) => {
+                    eprintln!("{color_red}Failed to read the checkpoint: {}{color_reset}", err);
+                    return ExitCode::FAILURE;
+                }
+            };
+            if let (Some(resumed), Some(path)) = (&resumed, &checkpoint) {
+                if resumed.search != params {
+                    eprintln!("Checkpoint {} belongs to a different search, run that search again or delete the checkpoint", path.display());
+                    return ExitCode::FAILURE;
+                }
+                targets = resumed.remaining(&targets);
+                if text {
+                    println!("Resuming {}: {} attempts, {} of {} wallet(s) found", path.display(), resumed.attempts, resumed.found.len(), requested);
+                }
+            }
 
-                        println!("Wallet address (bounceable, production): {color_yellow}{:?}{color_reset}", wallet.address.to_base64_url_flags(false, false));
-                        println!("Wallet address (non bounceable, production): {color_yellow}{:?}{color_reset}", wallet.address.to_base64_url_flags(true, false));
-                        println!("Wallet address (bounceable, non production): {color_yellow}{:?}{color_reset}", wallet.address.to_base64_url_flags(false, true));
-                        println!("Wallet address (non bounceable, non production): {color_yellow}{:?}{color_reset}", wallet.address.to_base64_url_flags(true, true));
+            let expected_attempts = expected_attempts(&targets, pattern.as_ref());
+            if expected_attempts == Some(f64::INFINITY) {
+                eprintln!("The vanity pattern can never match an address of the assigned shard");
+                return ExitCode::FAILURE;
+            }
+            if text {
+                if let Some(pattern) = &pattern {
+                    println!("Vanity pattern: {}", pattern);
+                }
+                match expected_attempts {
+                    Some(expected_attempts) => println!("Expected attempts: ~{:.0}", expected_attempts),
+                    None => println!("Expected attempts: unknown for regular expressions"),
+                }
+                if seed.is_some() {
+                    println!("{color_yellow}Keys are drawn from a seeded generator, do not use them for real funds{color_reset}");
+                }
+                if coordinator.is_none() {
+                    println!("Searching with {} threads", threads);
+                }
+            }
 
-                        println!("Wallet address(HEX): {color_yellow}{:?}{color_reset}", wallet.address.to_hex());
-                        println!("Account mnemonic: {color_bright_green}{:?}{color_reset}", mnemonic_string);
-                        sp.stop_with_newline();
+            let mode = match (split_key, key_format, subwallet) {
+                (Some(public_key), _, _) => SearchMode::SplitKey { public_key },
+                (None, _, true) => match subwallet_key(resumed.as_ref(), key_format, seed, password.as_deref()) {
+                    Ok((key_pair, mnemonic)) => SearchMode::Subwallet { key_pair, mnemonic },
+                    Err(err) => {
+                        eprintln!("{color_red}Failed to generate a key: {}{color_reset}", err);
+                        return ExitCode::FAILURE;
+                    }
+                },
+                (None, KeyFormat::Raw, false) => SearchMode::RawKey,
+                (None, KeyFormat::Mnemonic, false) => SearchMode::Mnemonic { password },
+            };
 
-                        break;
-                    } else {
-                        println!("{color_red}Shard is not equal to assigned shard, got: {:x?}, expect: {:x?}{color_reset}", account_shard, shard);
+            let config = SearchConfig {
+                version: wallet_version,
+                global_id: network.global_id(),
+                workchain,
+                threads,
+                pattern: pattern.clone(),
+                seed,
+                start: resumed.as_ref().map_or(0, |resumed| resumed.next_index),
+                end: None,
+            };
+            let coordinator = match coordinator.as_deref().map(|addr| start_coordinator(addr, &targets, &config, &mode, job_size)).transpose() {
+                Ok(coordinator) => coordinator,
+                Err(err) => {
+                    eprintln!("{color_red}Failed to start the coordinator: {}{color_reset}", err);
+                    return ExitCode::FAILURE;
+                }
+            };
+            if let (true, Some((_, listener))) = (text, &coordinator) {
+                match listener.local_addr() {
+                    Ok(addr) => println!("Waiting for workers on {}", addr),
+                    Err(err) => eprintln!("{color_red}Failed to read the coordinator address: {}{color_reset}", err),
+                }
+            }
+            let expected = targets.iter().map(|(shard, _)| shard.to_string()).collect::<Vec<String>>().join(", ");
+            let progress = SearchProgress::default();
+            let done = AtomicBool::new(false);
+            let show_progress = !quiet && !verbose && std::io::stderr().is_terminal();
+            catch_interrupts(true);
+            let searched = thread::scope(|scope| {
+                if show_progress {
+                    scope.spawn(|| report_progress(&progress, &done, requested, pattern.as_ref()));
+                }
+                scope.spawn(|| {
+                    while !done.load(Ordering::Relaxed) {
+                        if INTERRUPTED.load(Ordering::Relaxed) {
+                            progress.cancel();
+                        }
+                        thread::sleep(Duration::from_millis(50));
+                    }
+                });
+                if let Some((coordinator, listener)) = &coordinator {
+                    let searched = coordinator.run(listener, &progress);
+                    done.store(true, Ordering::Relaxed);
+                    return searched;
+                }
+                let report_miss = |address: &TonAddress, account_shard: Option<ShardIdent>| {
+                    if let Some(pattern) = pattern.as_ref().filter(|_| targets.iter().any(|(shard, _)| shard.contains_address(address))) {
+                        println!("{color_red}Address does not match the pattern, got: {}{color_reset}", pattern.address_form(address));
+                        return;
+                    }
+                    match account_shard {
+                        Some(account_shard) => println!("{color_red}Shard is not equal to assigned shard, got: {}, expect: {}{color_reset}", account_shard, expected),
+                        None => println!("Shard is not found"),
                     }
+                };
+                let on_miss: Option<OnMiss> = (text && verbose).then_some(&report_miss);
+                let searched = search_wallets(&net_shards, &targets, &config, &mode, &progress, on_miss);
+                done.store(true, Ordering::Relaxed);
+                searched
+            });
+            catch_interrupts(false);
+            let SearchResult { found, attempts, next_index, interrupted } = match searched {
+                Ok(result) => result,
+                Err(err) => {
+                    eprintln!("{color_red}Search failed: {}{color_reset}", err);
+                    return ExitCode::FAILURE;
+                }
+            };
+
+            let (mut found_before, attempts_before, elapsed_before) = resumed.map_or((Vec::new(), 0, 0.0), |resumed| (resumed.found, resumed.attempts, resumed.elapsed_secs));
+            let attempts = attempts_before + attempts;
+            let elapsed_secs = elapsed_before + start_time.elapsed().as_secs_f64();
+            found_before.extend(found.into_iter().map(|found| CheckpointWallet {
+                target: found.shard,
+                index: found.index,
+                wallet: GenerateOutput {
+                    wallet_version: wallet_version.to_string(),
+                    workchain,
+                    shard: format!("{:016x}", found.shard.prefix()),
+                    address: found.address.to_hex(),
+                    addresses: AddressForms::from(&found.address),
+                    mnemonic: found.mnemonic,
+                    password_protected,
+                    private_key: (key_format == KeyFormat::Raw).then(|| hex::encode(&found.key_pair.secret_key[..32])),
+                    private_key_base64: (key_format == KeyFormat::Raw).then(|| BASE64.encode(&found.key_pair.secret_key[..32])),
+                    public_key: split_key.map(|_| hex::encode(&found.key_pair.public_key)),
+                    split_key_offset: split_key.map(|_| found.offset),
+                    keystore: None,
+                    wallet_id: found.wallet_id,
+                    subwallet: subwallet.then_some(found.subwallet),
+                    attempts,
+                    elapsed_secs,
+                },
+            }));
+            let mut found = found_before;
+            found.sort_by_key(|wallet| wallet.target);
 
-                } else {
-                    println!("Shard is not found");
+            let mut status = ExitCode::SUCCESS;
+            if interrupted {
+                status = ExitCode::from(EXIT_INTERRUPTED);
+                eprintln!(
+                    "{color_yellow}Interrupted after {} attempts in {:.1}s ({:.1} attempts/sec), {} of {} wallets found{color_reset}",
+                    attempts,
+                    elapsed_secs,
+                    attempts as f64 / elapsed_secs,
+                    found.len(),
+                    requested
+                );
+                if let Some(path) = &checkpoint {
+                    let saved = Checkpoint::new(params, &mode, next_index, attempts, elapsed_secs, found.clone());
+                    match saved.write(path, keystore_password.as_deref()) {
+                        Ok(()) => eprintln!("Search state saved to {}, run the same command again to resume", path.display()),
+                        Err(err) => {
+                            eprintln!("{color_red}Failed to write the checkpoint {}: {}{color_reset}", path.display(), err);
+                            status = ExitCode::FAILURE;
+                        }
+                    }
+                }
+            } else {
+                if let Some(path) = checkpoint.as_ref().filter(|path| path.exists()) {
+                    if let Err(err) = std::fs::remove_file(path) {
+                        eprintln!("{color_red}Failed to remove the checkpoint {}: {}{color_reset}", path.display(), err);
+                    }
+                }
+                if found.len() < requested {
+                    match (&mode, &coordinator) {
+                        (SearchMode::Subwallet { .. }, None) => eprintln!(
+                            "{color_red}Only {} of {} wallets found, the subwallet ids of this key are exhausted at id {}{color_reset}",
+                            found.len(),
+                            requested,
+                            next_index.saturating_sub(1)
+                        ),
+                        _ => eprintln!("{color_red}Only {} of {} wallets found, the search ended early{color_reset}", found.len(), requested),
+                    }
+                    status = ExitCode::FAILURE;
                 }
             }
+            if found.is_empty() {
+                return status;
+            }
+            let mut outputs: Vec<GenerateOutput> = found.into_iter().map(|found| GenerateOutput { attempts, elapsed_secs, ..found.wallet }).collect();
+            if let (Some(path), Some(password)) = (keystore, &keystore_password) {
+                // the keystore holds what would have been printed
+                let sealed = if list { serde_json::to_vec(&outputs) } else { serde_json::to_vec(&outputs[0]) }
+                    .map_err(anyhow::Error::from)
+                    .and_then(|outputs| Ok(Keystore::seal(&outputs, password)?))
+                    .and_then(|sealed| Ok(sealed.write(&path)?));
+                if let Err(err) = sealed {
+                    eprintln!("{color_red}Failed to write keystore {}: {}{color_reset}", path.display(), err);
+                    return ExitCode::FAILURE;
+                }
+                for output in outputs.iter_mut() {
+                    output.mnemonic = None;
+                    output.private_key = None;
+                    output.private_key_base64 = None;
+                    output.keystore = Some(path.display().to_string());
+                }
+            }
+            print_generate(&outputs, cli.output, list);
+            return status;
+        }
+        Commands::Shard { address: Some(address), .. } if address != "-" => {
+            let output = match lookup_shard(&net_shards, &address) {
+                Ok(output) => output,
+                Err(err) => {
+                    eprintln!("{}", err);
+                    return ExitCode::FAILURE;
+                }
+            };
+            match cli.output {
+                OutputFormat::Text => output.print_text(),
+                OutputFormat::Json => print_json(&output),
+                OutputFormat::Csv => {
+                    println!("{}", ShardOutput::CSV_HEADER);
+                    println!("{}", output.csv_row());
+                }
+            }
+        }
+        Commands::Shard { file, .. } => {
+            let reader: Box<dyn BufRead> = match file {
+                Some(path) => match std::fs::File::open(&path) {
+                    Ok(file) => Box::new(std::io::BufReader::new(file)),
+                    Err(err) => {
+                        eprintln!("Failed to open {}: {}", path.display(), err);
+                        return ExitCode::FAILURE;
+                    }
+                },
+                None => Box::new(std::io::stdin().lock()),
+            };
 
-            println!("Elapsed time: {:?}", start_time.elapsed());
+            let mut batch = ShardBatchOutput::new(&net_shards);
+            for (line_number, line) in reader.lines().enumerate() {
+                let line = match line {
+                    Ok(line) => line,
+                    Err(err) => {
+                        eprintln!("Failed to read addresses: {}", err);
+                        return ExitCode::FAILURE;
+                    }
+                };
+                let address = line.trim();
+                if address.is_empty() {
+                    continue;
+                }
+                match lookup_shard(&net_shards, address) {
+                    Ok(output) => batch.add(output),
+                    Err(err) => {
+                        eprintln!("line {}: {}", line_number + 1, err);
+                        batch.invalid += 1;
+                    }
+                }
+            }
 
+            match cli.output {
+                OutputFormat::Text => batch.print_text(),
+                OutputFormat::Json => print_json(&batch),
+                OutputFormat::Csv => {
+                    println!("{}", ShardOutput::CSV_HEADER);
+                    for output in &batch.results {
+                        println!("{}", output.csv_row());
+                    }
+                }
+            }
+            if batch.invalid > 0 {
+                return ExitCode::FAILURE;
+            }
         }
-        Commands::Shard { address } => {
-            let ton_address = TonAddress::from_str(&address).unwrap();
-            match get_shard(&net_shards, ton_address.to_hex().as_str()) {
-                Some(shard) => println!("Shard: {:x?}", shard),
-                None => println!("Shard: Not found"),
+        Commands::Shards => {
+            let output = if cli.shards.is_some() || cli.split_depth.is_some() {
+                resolve_net_shards(&cli).await.map(|net_shards| ShardsOutput::new(&net_shards, None))
+            } else {
+                resolve_layout(&cli).await.map(|layout| ShardsOutput::new(&layout.shards(), Some(&layout)))
+            };
+            let output = match output {
+                Ok(output) => output,
+                Err(err) => {
+                    eprintln!("{}", err);
+                    return ExitCode::FAILURE;
+                }
+            };
+            match cli.output {
+                OutputFormat::Text => output.print_text(),
+                OutputFormat::Json => print_json(&output),
+                OutputFormat::Csv => {
+                    println!("{}", ShardsOutput::CSV_HEADER);
+                    output.csv_rows().for_each(|row| println!("{}", row));
+                }
+            }
+        }
+        Commands::Inspect { public_key, mnemonic_password, wallet_version, subwallets, workchain } => {
+            let public_key = match public_key {
+                Some(public_key) => hex::decode(public_key.trim_start_matches("0x")).map_err(anyhow::Error::from),
+                None => read_secret("Mnemonic").and_then(|mnemonic| {
+                    let password = mnemonic_password.then(|| read_password(MNEMONIC_PASSWORD_ENV, "Mnemonic password", false)).transpose()?;
+                    Ok(mnemonic_to_key_pair(&mnemonic, password.as_deref())?.public_key)
+                }),
+            };
+            let output = public_key.and_then(|public_key| {
+                let versions = wallet_version.map_or(WalletKind::ALL.to_vec(), |version| vec![version]);
+                let wallets = wallets_of_key(&public_key, &versions, subwallets, workchain, network.global_id())?;
+                Ok(InspectOutput::new(&public_key, &wallets, &net_shards))
+            });
+            let output = match output {
+                Ok(output) => output,
+                Err(err) => {
+                    eprintln!("{color_red}Failed to inspect the key: {}{color_reset}", err);
+                    return ExitCode::FAILURE;
+                }
+            };
+            match cli.output {
+                OutputFormat::Text => output.print_text(),
+                OutputFormat::Json => print_json(&output),
+                OutputFormat::Csv => {
+                    println!("{}", InspectOutput::CSV_HEADER);
+                    output.csv_rows().for_each(|row| println!("{}", row));
+                }
             }
         }
+        Commands::SplitKey { action: SplitKeyAction::New } => {
+            let base = BaseKey::generate();
+            let output = BaseKeyOutput { public_key: hex::encode(base.public_key()), secret_key: hex::encode(base.seed()) };
+            match cli.output {
+                OutputFormat::Text => output.print_text(),
+                OutputFormat::Json => print_json(&output),
+                OutputFormat::Csv => {
+                    println!("{}", BaseKeyOutput::CSV_HEADER);
+                    println!("{}", output.csv_row());
+                }
+            }
+        }
+        Commands::SplitKey { action: SplitKeyAction::Combine { wallet, address } } => {
+            let output = wallet.combine(network.global_id()).and_then(|combined| {
+                if let Some(expected) = &address {
+                    let expected = TonAddress::from_str(expected).map_err(|err| anyhow::anyhow!("Invalid address {:?}: {}", expected, err))?;
+                    combined.check_address(&expected)?;
+                }
+                Ok(CombinedKeyOutput::new(&combined, wallet.offset, wallet.wallet_version, &net_shards))
+            });
+            let output = match output {
+                Ok(output) => output,
+                Err(err) => {
+                    eprintln!("{color_red}Failed to combine the key: {}{color_reset}", err);
+                    return ExitCode::FAILURE;
+                }
+            };
+            match cli.output {
+                OutputFormat::Text => output.print_text(),
+                OutputFormat::Json => print_json(&output),
+                OutputFormat::Csv => {
+                    println!("{}", CombinedKeyOutput::CSV_HEADER);
+                    println!("{}", output.csv_row());
+                }
+            }
+        }
+        Commands::SplitKey { action: SplitKeyAction::Sign { wallet, body, deploy } } => {
+            let output = match wallet.combine(network.global_id()).and_then(|combined| SignedMessageOutput::new(&combined, &body, deploy)) {
+                Ok(output) => output,
+                Err(err) => {
+                    eprintln!("{color_red}Failed to sign the message: {}{color_reset}", err);
+                    return ExitCode::FAILURE;
+                }
+            };
+            match cli.output {
+                OutputFormat::Text => output.print_text(),
+                OutputFormat::Json => print_json(&output),
+                OutputFormat::Csv => {
+                    println!("{}", SignedMessageOutput::CSV_HEADER);
+                    println!("{}", output.csv_row());
+                }
+            }
+        }
+        Commands::Decrypt { path } => {
+            let opened = read_password(KEYSTORE_PASSWORD_ENV, "Keystore password", false)
+                .and_then(|password| Ok(Keystore::read(&path)?.open(&password)?))
+                .and_then(|plaintext| {
+                    // keystores hold a list of wallets or a single one, printed the same way
+                    match serde_json::from_slice::<Vec<GenerateOutput>>(&plaintext) {
+                        Ok(outputs) => Ok((outputs, true)),
+                        Err(_) => Ok((vec![serde_json::from_slice::<GenerateOutput>(&plaintext)?], false)),
+                    }
+                });
+            let (outputs, list) = match opened {
+                Ok(outputs) => outputs,
+                Err(err) => {
+                    eprintln!("{color_red}Failed to decrypt keystore {}: {}{color_reset}", path.display(), err);
+                    return ExitCode::FAILURE;
+                }
+            };
+            print_generate(&outputs, cli.output, list);
+        }
     }
+    ExitCode::SUCCESS
 }
 
-
 #[cfg(test)]
 mod tests {
     use super::*;
-    const SHARDS: [u64; 4] = [
-        0x2000000000000000,
-        0x6000000000000000,
-        0xA000000000000000,
-        0xE000000000000000,
-    ];
+    use tonlib::cell::CellBuilder;
+    use ton_shard_master::shard::MASTERCHAIN;
+
+    fn shards() -> Vec<ShardIdent> {
+        let mut net_shards = vec![ShardIdent::masterchain()];
+        net_shards.extend(shards_for_split_depth(BASECHAIN, 2).unwrap());
+        net_shards
+    }
+
     #[test]
-    fn it_works() {
+    fn inspect_lists_every_version_and_subwallet() {
+        let key_pair = mnemonic_to_key_pair("fancy carpet hello mandate penalty trial consider property top vicious exit rebuild tragic profit urban major total month holiday sudden rib gather media vicious", None).unwrap();
+        let wallets = wallets_of_key(&key_pair.public_key, &WalletKind::ALL, 3, BASECHAIN, -3).unwrap();
+        let output = InspectOutput::new(&key_pair.public_key, &wallets, &shards());
+        assert_eq!(output.wallets.len(), WalletKind::ALL.len() * 3);
+        let v4r2 = output.wallets.iter().find(|wallet| wallet.wallet_version == "v4r2" && wallet.subwallet == 0).unwrap();
+        assert_eq!(v4r2.bounceable, "EQCDM_QGggZ3qMa_f3lRPk4_qLDnLTqdi6OkMAV2NB9r5TG3");
+        assert_eq!(v4r2.shard.as_deref(), Some("0:a000000000000000"));
+        assert!(output.wallets.iter().all(|wallet| wallet.shard.is_some()));
+        assert_eq!(output.csv_rows().count(), output.wallets.len());
+    }
+
+    #[test]
+    fn shards_output_follows_uneven_splits() {
+        let mut net_shards = vec![ShardIdent::masterchain(), "0:c000000000000000".parse().unwrap()];
+        net_shards.extend(ShardIdent::from_prefix(BASECHAIN, 0, 1).unwrap().children().unwrap());
+        let output = ShardsOutput::new(&net_shards, None);
+        assert_eq!(
+            output.tree,
+            [
+                "workchain -1  -1:8000000000000000",
+                "workchain 0",
+                "├── 0",
+                "│   ├── 00  0:2000000000000000",
+                "│   └── 01  0:6000000000000000",
+                "└── 1  0:c000000000000000",
+            ]
+        );
+        assert_eq!(output.shards.iter().map(|entry| entry.keyspace_fraction).sum::<f64>(), 2.0);
+        assert_eq!(output.shards[1].prefix, "00");
+    }
+
+    #[test]
+    fn signed_messages_read_back() {
+        let wallet = CombinedWallet::new(&[42u8; 32], 3, WalletKind::V4R2, BASECHAIN, -3).unwrap();
+        let body = CellBuilder::new().store_i32(32, wallet.wallet_id()).unwrap().store_u32(32, 0).unwrap().build().unwrap();
+        let body = hex::encode(BagOfCells::from_root(body).serialize(false).unwrap());
+        let output = SignedMessageOutput::new(&wallet, &body, true).unwrap();
+        let message = BagOfCells::parse_base64(&output.message).unwrap();
+        assert_eq!(hex::encode(message.single_root().unwrap().cell_hash()), output.message_hash);
+        assert!(SignedMessageOutput::new(&wallet, "not a bag of cells", false).is_err());
+        assert!(parse_key("00").is_err());
+    }
+
+    #[test]
+    fn durations_are_human_readable() {
+        assert_eq!(format_duration(0.4), "0s");
+        assert_eq!(format_duration(59.0), "59s");
+        assert_eq!(format_duration(61.0), "1m 01s");
+        assert_eq!(format_duration(7500.0), "2h 05m");
+        assert_eq!(format_duration(90000.0), "1d 01h");
+        assert_eq!(format_duration(63_072_000.0), "2.0 years");
+        assert_eq!(format_duration(f64::INFINITY), "never");
+    }
 
-        let net_shards = SHARDS.to_vec();
+    #[test]
+    fn configs_name_their_network() {
+        assert!(Cli::try_parse_from(["ton-cli", "--config", "global.config.json", "shards"]).is_err());
+        let cli = Cli::try_parse_from(["ton-cli", "--network", "mainnet", "--config", "global.config.json", "shards"]).unwrap();
+        assert_eq!(cli.network().global_id(), ton_shard_master::network::MAINNET_GLOBAL_ID);
+        assert_eq!(Cli::try_parse_from(["ton-cli", "shards"]).unwrap().network(), Network::Testnet);
+    }
+
+    #[test]
+    fn csv_fields_are_quoted() {
+        let fields = ["V4R2", "/tmp/a,b.json", "say \"hi\"", "two\nlines", ""].map(String::from);
+        assert_eq!(csv_line(fields), "V4R2,\"/tmp/a,b.json\",\"say \"\"hi\"\"\",\"two\nlines\",");
+    }
 
-        let addresses_shard = [
-            ("0:af78316b56ee5f7e88f3558ad3b5ebbafd49304249e48dd33c9f27e63b7c8fe7",0xa000000000000000),
-            ("0:80fa1ebdd70277ca902d52cb2007cf910ca572b80f7c186fbb86e116cf4c66ba",0xa000000000000000),
-            ("0:923150e0c668cb309dc3d43449be197e17f5095378260e7715e278eaa80941ab",0xa000000000000000),
-            ("0:684c17d1138bcd4355aa88cc30dacba8cda4d8f3de4392cb5a7f4bec030190af",0x6000000000000000),
-            ("0:51cca3ff74207b3ed8f075740b126c320e795ec4f19f70b80d9cf919fc292594",0x6000000000000000),
-            ("0:b19a8a1821d01279aeb98e84a2ed002e4a30633264702b1059cebe73100d6b95",0xac000000000000000),
-        ];
+    #[test]
+    fn shard_lookup_accepts_every_address_form() {
+        let net_shards = shards();
+        let raw = "0:af78316b56ee5f7e88f3558ad3b5ebbafd49304249e48dd33c9f27e63b7c8fe7";
+        let address = TonAddress::from_str(raw).unwrap();
+        for form in [raw.to_string(), address.to_base64_url(), address.to_base64_std_flags(true, true)] {
+            let output = lookup_shard(&net_shards, &form).unwrap();
+            assert_eq!(output.address, raw);
+            assert_eq!(output.shard.as_deref(), Some("a000000000000000"));
+            assert_eq!(output.prefix_bits, Some(2));
+        }
+        let masterchain = lookup_shard(&net_shards, &raw.replacen("0:", "-1:", 1)).unwrap();
+        assert_eq!(masterchain.workchain, MASTERCHAIN);
+        assert_eq!(masterchain.shard.as_deref(), Some("8000000000000000"));
+        assert_eq!(masterchain.prefix_bits, Some(0));
+        assert!(lookup_shard(&net_shards, "not an address").is_err());
+    }
 
-        for (account_id, expect_shard) in addresses_shard {
-            let got_shard = get_shard(&net_shards, account_id);
-            assert_eq!(got_shard, Some(expect_shard), "shard must be equal, but got: {:x?}, expect: {:x?}, address: {:?}", got_shard, expect_shard, account_id);
+    #[test]
+    fn shard_histogram_counts_addresses() {
+        let net_shards = shards();
+        let mut batch = ShardBatchOutput::new(&net_shards);
+        for address in [
+            "0:af78316b56ee5f7e88f3558ad3b5ebbafd49304249e48dd33c9f27e63b7c8fe7",
+            "0:80fa1ebdd70277ca902d52cb2007cf910ca572b80f7c186fbb86e116cf4c66ba",
+            "0:684c17d1138bcd4355aa88cc30dacba8cda4d8f3de4392cb5a7f4bec030190af",
+            "-1:3333333333333333333333333333333333333333333333333333333333333333",
+        ] {
+            batch.add(lookup_shard(&net_shards, address).unwrap());
         }
+        assert_eq!(batch.results.len(), 4);
+        assert_eq!(batch.histogram["0:a000000000000000"], 2);
+        assert_eq!(batch.histogram["0:6000000000000000"], 1);
+        assert_eq!(batch.histogram["0:2000000000000000"], 0);
+        assert_eq!(batch.histogram["-1:8000000000000000"], 1);
+        assert_eq!(batch.not_found, 0);
     }
-}
\ No newline at end of file
+}
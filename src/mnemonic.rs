@@ -0,0 +1,157 @@
+//! Native TON mnemonic generation
+//!
+//! TON mnemonics are 24 words of the English wordlist without a checksum. A list of words is a
+//! valid mnemonic when its seed passes the version checks of tonlib and ton-crypto:
+//! - the "basic seed" of the words and the password starts with a zero byte,
+//! - with a password the "fast seed" of the words alone starts with 1, and the words alone don't
+//!   make a basic seed, so the password cannot be left out.
+//!
+//! tonlib-rs 0.17 reverses the basic seed check of password protected mnemonics and rejects the ones
+//! wallet apps generate, so mnemonics are checked and turned into keys here instead.
+
+use std::sync::OnceLock;
+
+use hmac::{Hmac, Mac};
+use pbkdf2::pbkdf2_hmac;
+use rand::{CryptoRng, Rng};
+use sha2::Sha512;
+use tonlib::mnemonic::{KeyPair, MnemonicError, WORDLIST_EN_SET};
+
+use crate::error::Result;
+use crate::wallet::key_pair_from_seed;
+
+/// Number of words of a TON mnemonic
+pub const WORD_COUNT: usize = 24;
+/// PBKDF2 iterations of the basic seed check
+const BASIC_SEED_ITERATIONS: u32 = 100_000 / 256;
+/// PBKDF2 iterations of the key derivation
+const KEY_ITERATIONS: u32 = 100_000;
+
+/// Words of the TON wordlist, in the order of their indices
+fn wordlist() -> &'static [&'static str] {
+    static WORDS: OnceLock<Vec<&'static str>> = OnceLock::new();
+    WORDS.get_or_init(|| {
+        let mut words: Vec<(&'static str, usize)> = WORDLIST_EN_SET.iter().map(|(&word, &index)| (word, index)).collect();
+        words.sort_by_key(|&(_, index)| index);
+        words.into_iter().map(|(word, _)| word).collect()
+    })
+}
+
+/// Generate a valid TON mnemonic, protected by `password` if given
+///
+/// Random word lists are drawn until one passes the seed checks, each check costing a few hundred
+/// hash rounds: 256 draws on average without a password, and about 65 536 with one, as the list
+/// has to pass both the password check and the basic seed check. No key is derived for the
+/// rejected lists.
+pub fn generate_mnemonic<R: Rng + CryptoRng>(rng: &mut R, password: Option<&str>) -> String {
+    let words = wordlist();
+    loop {
+        let mnemonic = (0..WORD_COUNT).map(|_| words[rng.gen_range(0..words.len())]).collect::<Vec<&str>>().join(" ");
+        if check_seed(&mnemonic, password).is_ok() {
+            return mnemonic;
+        }
+    }
+}
+
+/// Key pair of a mnemonic, the password has to match the one it was generated with
+pub fn mnemonic_to_key_pair(mnemonic: &str, password: Option<&str>) -> Result<KeyPair> {
+    let words: Vec<String> = mnemonic.split_whitespace().map(str::to_lowercase).collect();
+    if words.len() != WORD_COUNT {
+        return Err(MnemonicError::UnexpectedWordCount(words.len()).into());
+    }
+    if let Some(word) = words.iter().find(|word| !WORDLIST_EN_SET.contains_key(word.as_str())) {
+        return Err(MnemonicError::InvalidWord(word.clone()).into());
+    }
+    let mnemonic = words.join(" ");
+    check_seed(&mnemonic, password)?;
+
+    let mut seed = [0u8; 64];
+    pbkdf2_hmac::<Sha512>(&entropy(&mnemonic, password.filter(|password| !password.is_empty())), b"TON default seed", KEY_ITERATIONS, &mut seed);
+    Ok(key_pair_from_seed(seed[..32].try_into().expect("the seed is 64 bytes")))
+}
+
+/// Version checks of the seed of a mnemonic, cheapest first
+fn check_seed(mnemonic: &str, password: Option<&str>) -> std::result::Result<(), MnemonicError> {
+    let Some(password) = password.filter(|password| !password.is_empty()) else {
+        return match basic_seed_byte(&entropy(mnemonic, None)) {
+            0 => Ok(()),
+            byte => Err(MnemonicError::InvalidPasswordlessMenmonicFirstByte(byte)),
+        };
+    };
+    let passless = entropy(mnemonic, None);
+    match seed_byte(&passless, "TON fast seed version", 1) {
+        1 => {}
+        byte => return Err(MnemonicError::InvalidFirstByte(byte)),
+    }
+    if basic_seed_byte(&passless) == 0 {
+        // a valid mnemonic without the password
+        return Err(MnemonicError::InvalidFirstByte(0));
+    }
+    match basic_seed_byte(&entropy(mnemonic, Some(password))) {
+        0 => Ok(()),
+        byte => Err(MnemonicError::InvalidFirstByte(byte)),
+    }
+}
+
+/// HMAC-SHA512 of the password keyed with the space separated words
+fn entropy(mnemonic: &str, password: Option<&str>) -> [u8; 64] {
+    let mut mac = Hmac::<Sha512>::new_from_slice(mnemonic.as_bytes()).expect("HMAC accepts keys of any size");
+    if let Some(password) = password {
+        mac.update(password.as_bytes());
+    }
+    mac.finalize().into_bytes().into()
+}
+
+/// First byte of the PBKDF2-SHA512 of the entropy
+fn seed_byte(entropy: &[u8; 64], salt: &str, iterations: u32) -> u8 {
+    let mut seed = [0u8; 64];
+    pbkdf2_hmac::<Sha512>(entropy, salt.as_bytes(), iterations, &mut seed);
+    seed[0]
+}
+
+fn basic_seed_byte(entropy: &[u8; 64]) -> u8 {
+    seed_byte(entropy, "TON seed version", BASIC_SEED_ITERATIONS)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::OsRng;
+    use tonlib::mnemonic::Mnemonic;
+
+    #[test]
+    fn generated_mnemonics_are_valid() {
+        assert_eq!(wordlist().len(), 2048);
+        let mnemonic = generate_mnemonic(&mut OsRng, None);
+        assert_eq!(mnemonic.split(' ').count(), WORD_COUNT);
+        let key_pair = Mnemonic::from_str(&mnemonic, &None).unwrap().to_key_pair().unwrap();
+        assert!(mnemonic_to_key_pair(&mnemonic, None).unwrap() == key_pair);
+        assert!(mnemonic_to_key_pair(&mnemonic, Some("secret")).is_err());
+
+        let protected = generate_mnemonic(&mut OsRng, Some("secret"));
+        assert_eq!(basic_seed_byte(&entropy(&protected, Some("secret"))), 0);
+        assert!(mnemonic_to_key_pair(&protected, Some("secret")).is_ok());
+        assert!(mnemonic_to_key_pair(&protected, None).is_err());
+        assert!(mnemonic_to_key_pair(&protected, Some("other")).is_err());
+    }
+
+    #[test]
+    fn known_mnemonic_key() {
+        let mnemonic = "dose ice enrich trigger test dove century still betray gas diet dune use other base gym mad law immense village world example praise game";
+        assert_eq!(basic_seed_byte(&entropy(mnemonic, None)), 0);
+        let key_pair = mnemonic_to_key_pair(mnemonic, Some("")).unwrap();
+        assert_eq!(hex::encode(&key_pair.secret_key[..32]), "119dcf2840a3d56521d260b2f125eedc0d4f3795b9e627269a4b5a6dca8257bd");
+    }
+
+    #[test]
+    fn known_password_mnemonic_key() {
+        // generated with the mnemonicNew and mnemonicToPrivateKey checks of ton-crypto
+        let mnemonic = "yard dance envelope marble prefer step luxury call pond summer motor deputy omit piano ankle enough gather winter solve open warm leave sphere they";
+        let key_pair = mnemonic_to_key_pair(mnemonic, Some("secret")).unwrap();
+        assert_eq!(hex::encode(&key_pair.secret_key[..32]), "d6dff9c98ca521710a746ecf5613b9e6c773257eb5ecb3d5c6e67596d2e441f4");
+        assert_eq!(hex::encode(&key_pair.public_key), "e4f5987391426a123ab0369c14d82503749dbf72dc9000e47ef6a9e002b049d0");
+        assert!(mnemonic_to_key_pair(mnemonic, None).is_err());
+        // tonlib-rs rejects it, see the module docs
+        assert!(Mnemonic::from_str(mnemonic, &Some("secret".to_string())).is_err());
+    }
+}
@@ -0,0 +1,248 @@
+//! Network configs and shard discovery through the liteservers
+
+use std::fmt::{Display, Formatter};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tonlib::client::{TonClient, TonClientBuilder, TonClientInterface, TonConnectionParams};
+use tonlib::tl::{BlockIdExt, BlocksShards};
+
+use crate::error::Result;
+use crate::shard::ShardIdent;
+
+/// Embedded testnet global config
+pub const TESTNET_CONFIG: &str = include_str!("../testnet-global.config.json");
+/// Embedded mainnet global config
+pub const MAINNET_CONFIG: &str = include_str!("../mainnet-global.config.json");
+
+/// Global id of the testnet, part of the default V5R1 wallet id
+pub const TESTNET_GLOBAL_ID: i32 = -3;
+/// Global id of the mainnet, part of the default V5R1 wallet id
+pub const MAINNET_GLOBAL_ID: i32 = -239;
+
+/// TON networks with an embedded global config
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Network {
+    /// TON mainnet
+    Mainnet,
+    /// TON testnet
+    Testnet,
+}
+
+impl Network {
+    /// Every known network
+    pub const ALL: [Network; 2] = [Network::Mainnet, Network::Testnet];
+
+    /// Embedded global config of the network
+    pub fn config(&self) -> &'static str {
+        match self {
+            Network::Mainnet => MAINNET_CONFIG,
+            Network::Testnet => TESTNET_CONFIG,
+        }
+    }
+
+    /// Global id of the network
+    pub fn global_id(&self) -> i32 {
+        match self {
+            Network::Mainnet => MAINNET_GLOBAL_ID,
+            Network::Testnet => TESTNET_GLOBAL_ID,
+        }
+    }
+
+    /// Name of the network as used on the command line
+    pub fn name(&self) -> &'static str {
+        match self {
+            Network::Mainnet => "mainnet",
+            Network::Testnet => "testnet",
+        }
+    }
+}
+
+impl Display for Network {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for Network {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        Network::ALL
+            .into_iter()
+            .find(|network| network.name().eq_ignore_ascii_case(s))
+            .ok_or_else(|| format!("unknown network {:?}", s))
+    }
+}
+
+/// Load a global config from a file or an http(s) url
+pub async fn load_config(location: &str) -> Result<String> {
+    if location.starts_with("http://") || location.starts_with("https://") {
+        Ok(reqwest::get(location).await?.error_for_status()?.text().await?)
+    } else {
+        Ok(std::fs::read_to_string(location)?)
+    }
+}
+
+/// Fetch the current shard layout of every workchain from the liteservers of the given global config
+///
+/// The masterchain is never split and is returned as [`ShardIdent::masterchain`].
+pub async fn get_shards_from_network(config: &str) -> Result<Vec<ShardIdent>> {
+    Ok(ShardLayout::fetch(config).await?.shards())
+}
+
+/// Top block of a shard
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ShardBlock {
+    /// Shard the block belongs to
+    pub shard: ShardIdent,
+    /// Seqno of the block
+    pub seqno: u32,
+    /// Root hash of the block, in hex
+    pub root_hash: String,
+    /// File hash of the block, in hex
+    pub file_hash: String,
+}
+
+impl ShardBlock {
+    fn new(block: &BlockIdExt) -> Result<Self> {
+        // tonlib returns the hashes in base64
+        let hash = |hash: &str| BASE64.decode(hash).map(hex::encode).unwrap_or_else(|_| hash.to_string());
+        Ok(ShardBlock {
+            shard: ShardIdent::new(block.workchain, block.shard as u64)?,
+            seqno: block.seqno as u32,
+            root_hash: hash(&block.root_hash),
+            file_hash: hash(&block.file_hash),
+        })
+    }
+}
+
+/// Shard layout of a network at a masterchain block, as cached between runs
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ShardLayout {
+    /// Seqno of the masterchain block the layout was read from
+    pub masterchain_seqno: u32,
+    /// Unix time the layout was fetched at, in seconds
+    pub fetched_at: u64,
+    /// Top block of every shard, the masterchain block first
+    pub blocks: Vec<ShardBlock>,
+}
+
+impl ShardLayout {
+    /// Fetch the current layout from the liteservers of the given global config
+    pub async fn fetch(config: &str) -> Result<Self> {
+        TonClient::set_log_verbosity_level(0);
+        let client = TonClientBuilder::new()
+            .with_pool_size(10)
+            .with_connection_params(&TonConnectionParams{
+                config: config.to_string(),
+                ..Default::default()
+            })
+            .build()
+            .await?;
+
+        let (_, info) = client.get_masterchain_info().await?;
+        let block_shards: BlocksShards = client.get_block_shards(&info.last).await?;
+
+        Ok(ShardLayout {
+            masterchain_seqno: info.last.seqno as u32,
+            fetched_at: SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |now| now.as_secs()),
+            blocks: std::iter::once(&info.last)
+                .chain(&block_shards.shards)
+                .map(ShardBlock::new)
+                .collect::<Result<_>>()?,
+        })
+    }
+
+    /// Shards of every workchain, the masterchain first
+    pub fn shards(&self) -> Vec<ShardIdent> {
+        self.blocks.iter().map(|block| block.shard).collect()
+    }
+
+    /// Top block of `shard`
+    pub fn block(&self, shard: ShardIdent) -> Option<&ShardBlock> {
+        self.blocks.iter().find(|block| block.shard == shard)
+    }
+
+    /// Time since the layout was fetched
+    pub fn age(&self) -> Duration {
+        let fetched_at = UNIX_EPOCH + Duration::from_secs(self.fetched_at);
+        SystemTime::now().duration_since(fetched_at).unwrap_or_default()
+    }
+
+    /// Save the layout as JSON, creating the directory if needed
+    pub fn write(&self, path: &Path) -> Result<()> {
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        std::fs::write(path, serde_json::to_string_pretty(self)?)?;
+        Ok(())
+    }
+
+    /// Load a layout saved with [`ShardLayout::write`]
+    pub fn read(path: &Path) -> Result<Self> {
+        Ok(serde_json::from_str(&std::fs::read_to_string(path)?)?)
+    }
+}
+
+/// File caching the shard layout of `network`, or of the global config at `config` when given
+///
+/// Layouts are cached in `$XDG_CACHE_HOME/ton-shard-master`, `~/.cache/ton-shard-master` or
+/// `%LOCALAPPDATA%\ton-shard-master`. `None` if none of them is known.
+pub fn layout_cache_path(network: Network, config: Option<&str>) -> Option<PathBuf> {
+    let dir = std::env::var_os("XDG_CACHE_HOME")
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| Path::new(&home).join(".cache")))
+        .or_else(|| std::env::var_os("LOCALAPPDATA").map(PathBuf::from))?
+        .join("ton-shard-master");
+    let name = match config {
+        Some(config) => format!("shards-{}.json", &hex::encode(Sha256::digest(config.as_bytes()))[..16]),
+        None => format!("shards-{}.json", network.name()),
+    };
+    Some(dir.join(name))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::shard::shards_for_split_depth;
+
+    #[test]
+    fn cached_layout_reads_back() {
+        let path = std::env::temp_dir().join(format!("ton-shard-master-{}", std::process::id())).join("shards.json");
+        let block = |shard, seqno| ShardBlock { shard, seqno, root_hash: "00".repeat(32), file_hash: "ff".repeat(32) };
+        let mut blocks = vec![block(ShardIdent::masterchain(), 42)];
+        blocks.extend(shards_for_split_depth(0, 2).unwrap().into_iter().map(|shard| block(shard, 7)));
+        let layout = ShardLayout { masterchain_seqno: 42, fetched_at: 1_700_000_000, blocks };
+        layout.write(&path).unwrap();
+        assert_eq!(ShardLayout::read(&path).unwrap(), layout);
+        assert_eq!(layout.shards()[0], ShardIdent::masterchain());
+        assert_eq!(layout.block(shards_for_split_depth(0, 2).unwrap()[1]).unwrap().seqno, 7);
+        assert!(std::fs::read_to_string(&path).unwrap().contains("\"-1:8000000000000000\""));
+        assert!(layout.age() > Duration::from_secs(3600));
+        std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn block_hashes_are_read_as_hex() {
+        let block = BlockIdExt { workchain: 0, shard: i64::MIN, seqno: 9, root_hash: BASE64.encode([0xab; 32]), file_hash: "not base64!".to_string() };
+        let block = ShardBlock::new(&block).unwrap();
+        assert_eq!(block.shard, ShardIdent::full(0));
+        assert_eq!(block.root_hash, "ab".repeat(32));
+        assert_eq!(block.file_hash, "not base64!");
+    }
+
+    #[test]
+    fn custom_configs_are_cached_apart() {
+        let Some(testnet) = layout_cache_path(Network::Testnet, None) else { return };
+        assert!(testnet.ends_with("ton-shard-master/shards-testnet.json"));
+        let custom = layout_cache_path(Network::Testnet, Some("https://example.com/global.config.json")).unwrap();
+        assert_ne!(custom, layout_cache_path(Network::Testnet, Some("./global.config.json")).unwrap());
+        assert_eq!(custom.parent(), testnet.parent());
+    }
+}
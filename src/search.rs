@@ -0,0 +1,885 @@
+//! Parallel search for wallets landing in given shards
+
+use std::borrow::Cow;
+use std::fmt;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::thread;
+
+use rand::SeedableRng;
+use rand_chacha::ChaCha20Rng;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use tonlib::address::TonAddress;
+use tonlib::mnemonic::KeyPair;
+
+use crate::error::{Error, Result};
+use crate::keystore::{write_private, Keystore};
+use crate::mnemonic::mnemonic_to_key_pair;
+use crate::shard::ShardIdent;
+use crate::splitkey::{decompress, OffsetWalk};
+use crate::vanity::VanityPattern;
+use crate::wallet::{generate_key_pair, generate_key_pair_from, generate_raw_key_pair, generate_raw_key_pair_from, key_pair_from_seed, AddressDeriver, WalletKind};
+
+/// Source of the candidate wallets tried by the search
+#[derive(Clone)]
+pub enum SearchMode {
+    /// Generate a new mnemonic for every attempt
+    Mnemonic {
+        /// Password protecting the generated mnemonics
+        password: Option<String>,
+    },
+    /// Generate a random Ed25519 key for every attempt, without a mnemonic
+    RawKey,
+    /// Keep one key pair and iterate over its subwallet ids
+    Subwallet {
+        /// Key pair shared by every candidate
+        key_pair: KeyPair,
+        /// Mnemonic of `key_pair`, `None` for raw keys
+        mnemonic: Option<String>,
+    },
+    /// Walk the offsets of someone else's public key, see [`crate::splitkey`]
+    SplitKey {
+        /// Base public key of the owner
+        public_key: [u8; 32],
+    },
+}
+
+/// Wallet that landed in the requested shard
+pub struct FoundWallet {
+    /// Address of the wallet
+    pub address: TonAddress,
+    /// Key pair of the wallet, without secret key in split-key mode
+    pub key_pair: KeyPair,
+    /// Mnemonic of the wallet key, `None` for raw keys
+    pub mnemonic: Option<String>,
+    /// Wallet id the address was derived with
+    pub wallet_id: i32,
+    /// Subwallet number, 0 unless searching in subwallet mode
+    pub subwallet: u32,
+    /// Offset from the base key, 0 unless searching in split-key mode
+    pub offset: u64,
+    /// Shard the wallet belongs to
+    pub shard: ShardIdent,
+    /// Index of the candidate, see [`SearchConfig::start`]
+    pub index: u64,
+}
+
+/// Parameters shared by all workers of a search
+#[derive(Clone)]
+pub struct SearchConfig {
+    /// Wallet contract version
+    pub version: WalletKind,
+    /// Global id of the network, see [`crate::network::Network::global_id`]
+    pub global_id: i32,
+    /// Workchain the wallets are deployed to
+    pub workchain: i32,
+    /// Number of worker threads
+    pub threads: usize,
+    /// Vanity pattern the address has to match in addition to its target shard
+    pub pattern: Option<VanityPattern>,
+    /// Draw the keys of mnemonic and raw key searches from this seed instead of the OS, see
+    /// [`candidate_rng`]
+    pub seed: Option<u64>,
+    /// Index of the first candidate, to resume a search at [`SearchResult::next_index`]
+    ///
+    /// Candidates are numbered from 0: the index is the subwallet id, the split-key offset, or the
+    /// stream of the seeded generator.
+    pub start: u64,
+    /// Index at which the search gives up, to split a search into ranges
+    pub end: Option<u64>,
+}
+
+impl SearchConfig {
+    /// Single threaded search of a whole keyspace without a pattern or seed
+    pub fn new(version: WalletKind, global_id: i32, workchain: i32) -> Self {
+        SearchConfig { version, global_id, workchain, threads: 1, pattern: None, seed: None, start: 0, end: None }
+    }
+}
+
+/// Live state of a running search, for progress displays
+#[derive(Default)]
+pub struct SearchProgress {
+    pub(crate) attempts: AtomicU64,
+    pub(crate) remaining: Mutex<Vec<(ShardIdent, usize)>>,
+    cancelled: AtomicBool,
+}
+
+impl SearchProgress {
+    /// Number of wallets tried so far
+    pub fn attempts(&self) -> u64 {
+        self.attempts.load(Ordering::Relaxed)
+    }
+
+    /// Targets with the number of wallets still missing in each of them
+    pub fn remaining(&self) -> Vec<(ShardIdent, usize)> {
+        self.remaining.lock().unwrap().clone()
+    }
+
+    /// Stop the search, it returns the wallets found so far
+    ///
+    /// Cancelling is final, later searches with the same progress stop right away.
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+    }
+
+    /// Whether [`SearchProgress::cancel`] was called
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+    }
+}
+
+/// Outcome of a parallel wallet search
+pub struct SearchResult {
+    /// Found wallets, sorted by shard
+    pub found: Vec<FoundWallet>,
+    /// Number of wallets tried
+    pub attempts: u64,
+    /// Every candidate from [`SearchConfig::start`] up to this index was tried
+    ///
+    /// A search of subwallet ids, split-key offsets or seeded keys tries the same candidates again
+    /// when it is started at this index. Wallets found at higher indices are found again.
+    pub next_index: u64,
+    /// Whether the search was stopped by [`SearchProgress::cancel`]
+    pub interrupted: bool,
+}
+
+/// Secret key format of the wallets of a search
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum KeyFormat {
+    /// A 24 word TON mnemonic
+    Mnemonic,
+    /// A raw 32 byte Ed25519 private key, much faster to generate
+    Raw,
+}
+
+impl KeyFormat {
+    /// Every key format
+    pub const ALL: [KeyFormat; 2] = [KeyFormat::Mnemonic, KeyFormat::Raw];
+
+    /// Name of the format as used on the command line
+    pub fn name(&self) -> &'static str {
+        match self {
+            KeyFormat::Mnemonic => "mnemonic",
+            KeyFormat::Raw => "raw",
+        }
+    }
+}
+
+impl fmt::Display for KeyFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for KeyFormat {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        KeyFormat::ALL
+            .into_iter()
+            .find(|format| format.name() == s)
+            .ok_or_else(|| Error::InvalidKey(format!("unknown key format {:?}", s)))
+    }
+}
+
+/// Parameters of a search, a checkpoint only resumes the search it was written by
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SearchParams {
+    /// Wallet contract version
+    pub wallet_version: String,
+    /// Global id of the network
+    pub global_id: i32,
+    /// Workchain the wallets are deployed to
+    pub workchain: i32,
+    /// Target shards with the number of wallets requested in each of them
+    pub targets: Vec<(ShardIdent, usize)>,
+    /// Vanity pattern, as displayed
+    pub pattern: Option<String>,
+    /// Secret key format of the wallets
+    pub key_format: KeyFormat,
+    /// Whether the mnemonics are protected by a password
+    pub password_protected: bool,
+    /// Whether the search iterates over the subwallet ids of one key
+    pub subwallet: bool,
+    /// Base public key (hex) of a split-key search
+    pub split_key: Option<String>,
+    /// Seed of the generator the keys are drawn from
+    pub seed: Option<u64>,
+}
+
+impl SearchParams {
+    /// Whether the candidates are numbered, so the search can resume at the next untried one
+    pub fn resumable(&self) -> bool {
+        self.subwallet || self.split_key.is_some() || self.seed.is_some()
+    }
+}
+
+/// Wallet found before a checkpoint, with the target it counts for
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct CheckpointWallet<W> {
+    /// Target shard the wallet was found for
+    pub target: ShardIdent,
+    /// Index of the candidate, see [`FoundWallet::index`]
+    pub index: u64,
+    /// The wallet, as the caller prints it
+    pub wallet: W,
+}
+
+/// State of an interrupted search, to resume it later
+///
+/// It holds the mnemonic or private key of subwallet searches and the found wallets, the file is
+/// sealed like a [`Keystore`] when written with a password.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Checkpoint<W> {
+    /// Parameters of the search
+    pub search: SearchParams,
+    /// Mnemonic of a subwallet search
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mnemonic: Option<String>,
+    /// Raw private key (hex) of a subwallet search
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub private_key: Option<String>,
+    /// Every candidate below this index was tried
+    pub next_index: u64,
+    /// Attempts of every run so far
+    pub attempts: u64,
+    /// Search time of every run so far
+    pub elapsed_secs: f64,
+    /// Wallets found so far
+    pub found: Vec<CheckpointWallet<W>>,
+}
+
+impl<W> Checkpoint<W> {
+    /// Checkpoint of a search interrupted at `next_index`
+    ///
+    /// The key of a subwallet search is kept. Resumable searches drop the wallets found past
+    /// `next_index`, they are found again when resuming.
+    pub fn new(search: SearchParams, mode: &SearchMode, next_index: u64, attempts: u64, elapsed_secs: f64, found: Vec<CheckpointWallet<W>>) -> Self {
+        let (mnemonic, private_key) = match mode {
+            SearchMode::Subwallet { mnemonic: Some(mnemonic), .. } => (Some(mnemonic.clone()), None),
+            SearchMode::Subwallet { key_pair, mnemonic: None } => (None, Some(hex::encode(&key_pair.secret_key[..32]))),
+            _ => (None, None),
+        };
+        let resumable = search.resumable();
+        let found = found.into_iter().filter(|wallet| !resumable || wallet.index < next_index).collect();
+        Checkpoint { search, mnemonic, private_key, next_index, attempts, elapsed_secs, found }
+    }
+
+    /// Targets of the search less the wallets found before the checkpoint
+    pub fn remaining(&self, targets: &[(ShardIdent, usize)]) -> Vec<(ShardIdent, usize)> {
+        let mut targets = targets.to_vec();
+        for wallet in &self.found {
+            if let Some((_, count)) = targets.iter_mut().find(|(shard, count)| *count > 0 && *shard == wallet.target) {
+                *count -= 1;
+            }
+        }
+        targets
+    }
+
+    /// Key pair and mnemonic of a subwallet search, `None` for the other searches
+    pub fn subwallet_key(&self, password: Option<&str>) -> Result<Option<(KeyPair, Option<String>)>> {
+        if let Some(mnemonic) = &self.mnemonic {
+            return Ok(Some((mnemonic_to_key_pair(mnemonic, password)?, Some(mnemonic.clone()))));
+        }
+        let Some(private_key) = &self.private_key else {
+            return Ok(None);
+        };
+        let seed: [u8; 32] = hex::decode(private_key)
+            .ok()
+            .and_then(|seed| seed.try_into().ok())
+            .ok_or_else(|| Error::InvalidKey("the private key of the checkpoint is not 32 bytes of hex".to_string()))?;
+        Ok(Some((key_pair_from_seed(&seed), None)))
+    }
+}
+
+impl<W: Serialize + DeserializeOwned> Checkpoint<W> {
+    /// Read a checkpoint written by [`Checkpoint::write`] with the same password
+    pub fn read(path: &Path, password: Option<&str>) -> Result<Self> {
+        let contents = std::fs::read(path)?;
+        let sealed = serde_json::from_slice::<Keystore>(&contents).ok();
+        match (sealed, password) {
+            (Some(sealed), Some(password)) => Ok(serde_json::from_slice(&sealed.open(password)?)?),
+            (Some(_), None) => Err(Error::Keystore("the checkpoint is encrypted, pass the keystore it was written with".to_string())),
+            (None, _) => Ok(serde_json::from_slice(&contents)?),
+        }
+    }
+
+    /// Write the checkpoint to `path` for its owner only, sealed with `password` if given
+    pub fn write(&self, path: &Path, password: Option<&str>) -> Result<()> {
+        let mut contents = serde_json::to_vec_pretty(self)?;
+        if let Some(password) = password {
+            contents = serde_json::to_vec_pretty(&Keystore::seal(&contents, password)?)?;
+        }
+        write_private(path, &contents)
+    }
+}
+
+/// Callback of [`search_wallets`] for the wallets that missed every target
+pub type OnMiss<'a> = &'a (dyn Fn(&TonAddress, Option<ShardIdent>) + Sync);
+
+/// Search for wallets using `threads` workers until every target shard got its wallets
+///
+/// `targets` lists the wanted shards together with the number of wallets to find in each of them,
+/// all of them in the configured workchain. Targets do not need to be part of `net_shards`, a deeper
+/// prefix pins the wallets to the same shard even after future splits.
+/// All workers stop as soon as the last target is satisfied. The search only gives up early in
+/// subwallet mode, once every subwallet id has been tried, at [`SearchConfig::end`], when a wallet
+/// cannot be derived, or when `progress` is cancelled.
+/// In split-key mode the found wallets carry their offset, to be combined by the owner of the key
+/// with [`crate::splitkey::BaseKey::combine`].
+///
+/// With a [`VanityPattern`] a wallet only counts if its address also matches the pattern, use the
+/// full shard of the workchain as target to search for the pattern alone.
+///
+/// `progress` is updated while the search runs and can be watched from another thread.
+/// `on_miss`, if given, is called with every wallet address that did not fit a target and its
+/// current shard, `None` if the wallet is outside of `net_shards`. Without it misses cost nothing.
+pub fn search_wallets(
+    net_shards: &[ShardIdent],
+    targets: &[(ShardIdent, usize)],
+    config: &SearchConfig,
+    mode: &SearchMode,
+    progress: &SearchProgress,
+    on_miss: Option<OnMiss>,
+) -> Result<SearchResult> {
+    let SearchConfig { version, global_id, workchain, threads, ref pattern, seed, start, end } = *config;
+    if let Some((shard, _)) = targets.iter().find(|(shard, _)| shard.workchain() != workchain) {
+        return Err(Error::InvalidShard(format!("{} is not in workchain {}", shard, workchain)));
+    }
+    let threads = threads.max(1);
+    let deriver = AddressDeriver::new(version)?;
+    let split_base = match mode {
+        SearchMode::SplitKey { public_key } => Some(decompress(public_key)?),
+        _ => None,
+    };
+    let stop = AtomicBool::new(targets.iter().all(|&(_, count)| count == 0));
+    let next_index = AtomicU64::new(u64::MAX);
+    let SearchProgress { attempts, remaining, cancelled } = progress;
+    attempts.store(0, Ordering::Relaxed);
+    *remaining.lock().unwrap() = targets.to_vec();
+    let found: Mutex<Vec<FoundWallet>> = Mutex::new(Vec::new());
+    let error: Mutex<Option<Error>> = Mutex::new(None);
+
+    thread::scope(|scope| {
+        for worker in 0..threads {
+            let (stop, next_index, found, error, deriver) = (&stop, &next_index, &found, &error, &deriver);
+            scope.spawn(move || {
+                let fail = |err: Error| {
+                    error.lock().unwrap().get_or_insert(err);
+                    stop.store(true, Ordering::SeqCst);
+                };
+                // candidates are interleaved between the workers
+                let mut index = start + worker as u64;
+                let mut walk = split_base.map(|base| OffsetWalk::new(base, index, threads as u64));
+                while !stop.load(Ordering::Relaxed) && !cancelled.load(Ordering::Relaxed) && end.is_none_or(|end| index < end) {
+                    let (key_pair, mnemonic, subwallet, offset) = match mode {
+                        SearchMode::Mnemonic { password } => {
+                            let generated = match seed {
+                                Some(seed) => generate_key_pair_from(&mut candidate_rng(seed, index), password.as_deref()),
+                                None => generate_key_pair(password.as_deref()),
+                            };
+                            match generated {
+                                Ok((key_pair, mnemonic)) => (Cow::Owned(key_pair), Some(Cow::Owned(mnemonic)), 0, 0),
+                                Err(err) => return fail(err),
+                            }
+                        }
+                        SearchMode::RawKey => {
+                            let key_pair = match seed {
+                                Some(seed) => generate_raw_key_pair_from(&mut candidate_rng(seed, index)),
+                                None => generate_raw_key_pair(),
+                            };
+                            (Cow::Owned(key_pair), None, 0, 0)
+                        }
+                        SearchMode::SplitKey { .. } => {
+                            let (offset, key_pair) = walk.as_mut().expect("split-key searches have a walk").next_key();
+                            (Cow::Owned(key_pair), None, 0, offset)
+                        }
+                        SearchMode::Subwallet { key_pair, mnemonic } => {
+                            if index > version.max_subwallet() as u64 {
+                                break;
+                            }
+                            // the key is only cloned for the wallets that are found
+                            (Cow::Borrowed(key_pair), mnemonic.as_deref().map(Cow::Borrowed), index as u32, 0)
+                        }
+                    };
+                    let candidate = index;
+                    index += threads as u64;
+                    let wallet_id = version.wallet_id(global_id, workchain, subwallet);
+                    let address = match deriver.derive(&key_pair, workchain, wallet_id) {
+                        Ok(address) => address,
+                        Err(err) => return fail(err),
+                    };
+                    attempts.fetch_add(1, Ordering::Relaxed);
+
+                    let fits = targets.iter().any(|(shard, _)| shard.contains_address(&address));
+                    if fits && pattern.as_ref().is_none_or(|pattern| pattern.matches(&address)) {
+                        let mut remaining = remaining.lock().unwrap();
+                        if let Some((shard, count)) = remaining.iter_mut().find(|(shard, count)| *count > 0 && shard.contains_address(&address)) {
+                            *count -= 1;
+                            found.lock().unwrap().push(FoundWallet {
+                                address,
+                                key_pair: key_pair.into_owned(),
+                                mnemonic: mnemonic.map(Cow::into_owned),
+                                wallet_id,
+                                subwallet,
+                                offset,
+                                shard: *shard,
+                                index: candidate,
+                            });
+                            if found_all(&remaining) {
+                                stop.store(true, Ordering::SeqCst);
+                            }
+                        }
+                        // a wallet beyond the requested count fits as well, it is not a miss
+                        continue;
+                    }
+                    if let Some(on_miss) = on_miss {
+                        on_miss(&address, net_shards.iter().copied().find(|shard| shard.contains_address(&address)));
+                    }
+                }
+                next_index.fetch_min(index, Ordering::SeqCst);
+            });
+        }
+    });
+
+    if let Some(err) = error.into_inner().unwrap() {
+        return Err(err);
+    }
+    let mut found = found.into_inner().unwrap();
+    found.sort_by_key(|wallet| wallet.shard);
+    let interrupted = progress.is_cancelled() && !found_all(&progress.remaining());
+    Ok(SearchResult {
+        found,
+        attempts: progress.attempts(),
+        next_index: next_index.into_inner(),
+        interrupted,
+    })
+}
+
+fn found_all(remaining: &[(ShardIdent, usize)]) -> bool {
+    remaining.iter().all(|&(_, count)| count == 0)
+}
+
+/// Generator of the candidate `index` of a search seeded with `seed`
+///
+/// Every candidate reads its own stream of a ChaCha20 generator, so seeded searches try the same
+/// keys whatever the number of workers, and can resume at any index.
+pub fn candidate_rng(seed: u64, index: u64) -> ChaCha20Rng {
+    let mut rng = ChaCha20Rng::seed_from_u64(seed);
+    rng.set_stream(index);
+    rng
+}
+
+/// Expected number of attempts until every target got its wallets
+///
+/// A random wallet lands in a shard with a prefix of `n` bits with probability `2^-n`, and matches
+/// `pattern` there with [`VanityPattern::probability`]. The estimate is exact for disjoint targets:
+/// with attempts arriving as a Poisson process of rate 1, the expected number of attempts equals the
+/// expected time until every target received its count, which is the integral of the probability
+/// that some target is still short.
+///
+/// Returns `None` when the difficulty of the pattern is unknown (regular expressions), and infinity
+/// when some target can never be satisfied.
+pub fn expected_attempts(targets: &[(ShardIdent, usize)], pattern: Option<&VanityPattern>) -> Option<f64> {
+    // identical targets are grouped, so layouts of thousands of shards stay cheap
+    let mut groups: Vec<(f64, usize, i32)> = Vec::new();
+    for &(shard, count) in targets.iter().filter(|(_, count)| *count > 0) {
+        let matching = match pattern {
+            Some(pattern) => pattern.probability(&shard)?,
+            None => 1.0,
+        };
+        let probability = 0.5f64.powi(shard.prefix_len() as i32) * matching;
+        if probability == 0.0 {
+            return Some(f64::INFINITY);
+        }
+        match groups.iter_mut().find(|(p, c, _)| *p == probability && *c == count) {
+            Some((_, _, n)) => *n += 1,
+            None => groups.push((probability, count, 1)),
+        }
+    }
+    if groups.is_empty() {
+        return Some(0.0);
+    }
+
+    // probability that every target is done at time t
+    let done = |t: f64| -> f64 {
+        groups
+            .iter()
+            .map(|&(probability, count, n)| poisson_at_least(probability * t, count).powi(n))
+            .product()
+    };
+
+    // integrate 1 - done(t) over t = e^u, the integrand is ~1 below `start`
+    let start = groups.iter().map(|&(probability, count, _)| count as f64 / probability).fold(f64::INFINITY, f64::min) * 1e-3;
+    let step = 0.005;
+    let mut expected = start;
+    let mut u = start.ln();
+    let mut previous = (1.0 - done(start)) * start;
+    loop {
+        u += step;
+        let t = u.exp();
+        let current = (1.0 - done(t)) * t;
+        expected += (previous + current) / 2.0 * step;
+        if current < expected * 1e-12 {
+            return Some(expected);
+        }
+        previous = current;
+    }
+}
+
+/// `P(X >= count)` for `X ~ Poisson(lambda)`
+fn poisson_at_least(lambda: f64, count: usize) -> f64 {
+    // the terms are summed in log space, `e^-lambda` alone underflows for large counts
+    let mut log_term = -lambda;
+    let mut below = 0.0;
+    for j in 0..count {
+        below += log_term.exp();
+        log_term += (lambda / (j + 1) as f64).ln();
+    }
+    (1.0 - below).max(0.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::TESTNET_GLOBAL_ID;
+    use crate::shard::{get_shard, shards_for_split_depth, MASTERCHAIN};
+    use crate::splitkey::BaseKey;
+    use crate::wallet::{derive_wallet_address, key_pair_from_seed};
+
+    fn config(version: WalletKind, threads: usize) -> SearchConfig {
+        SearchConfig { threads, ..SearchConfig::new(version, TESTNET_GLOBAL_ID, 0) }
+    }
+
+    #[test]
+    fn batch_search_fills_every_shard() {
+        let net_shards = shards_for_split_depth(0, 2).unwrap();
+        let (key_pair, mnemonic) = generate_key_pair(None).unwrap();
+        let mode = SearchMode::Subwallet { key_pair, mnemonic: Some(mnemonic) };
+        let targets: Vec<(ShardIdent, usize)> = net_shards.iter().map(|&shard| (shard, 2)).collect();
+
+        let config = config(WalletKind::V4R2, 3);
+        let progress = SearchProgress::default();
+        let SearchResult { found, attempts, .. } = search_wallets(&net_shards, &targets, &config, &mode, &progress, None).unwrap();
+        assert_eq!(found.len(), 8);
+        assert!(attempts >= 8);
+        assert_eq!(progress.attempts(), attempts);
+        assert!(progress.remaining().iter().all(|&(_, count)| count == 0));
+        for (i, wallet) in found.iter().enumerate() {
+            assert_eq!(wallet.shard, net_shards[i / 2]);
+            assert_eq!(get_shard(&net_shards, &wallet.address.to_hex()), Some(wallet.shard));
+        }
+        let mut subwallets: Vec<u32> = found.iter().map(|wallet| wallet.subwallet).collect();
+        subwallets.sort();
+        subwallets.dedup();
+        assert_eq!(subwallets.len(), 8);
+    }
+
+    #[test]
+    fn worker_pool_stops_on_the_first_hit() {
+        let net_shards = shards_for_split_depth(0, 2).unwrap();
+        let mode = SearchMode::Subwallet { key_pair: key_pair_from_seed(&[1; 32]), mnemonic: None };
+        let first_hit = |threads| {
+            let progress = SearchProgress::default();
+            let result = search_wallets(&net_shards, &[(net_shards[0], 1)], &config(WalletKind::V4R2, threads), &mode, &progress, None).unwrap();
+            assert_eq!(progress.attempts(), result.attempts);
+            result
+        };
+
+        let single = first_hit(1);
+        assert_eq!(single.attempts, single.found[0].index + 1);
+        // the attempts of every worker add up, and all of them stop long before the ids run out
+        let pool = first_hit(8);
+        assert_eq!(pool.found.len(), 1);
+        assert!(pool.found[0].index >= single.found[0].index);
+        assert!(pool.attempts > pool.found[0].index / 8 && pool.attempts < 10_000);
+        assert!(net_shards[0].contains_address(&pool.found[0].address));
+    }
+
+    #[test]
+    fn subwallet_search_finds_wallet_in_shard() {
+        let net_shards = shards_for_split_depth(0, 2).unwrap();
+        let (key_pair, mnemonic) = generate_key_pair(None).unwrap();
+        let mode = SearchMode::Subwallet { key_pair: key_pair.clone(), mnemonic: Some(mnemonic.clone()) };
+
+        let misses = AtomicU64::new(0);
+        let config = config(WalletKind::V4R2, 2);
+        let SearchResult { found, attempts, .. } = search_wallets(&net_shards, &[(net_shards[1], 1)], &config, &mode, &SearchProgress::default(), Some(&|_, shard| {
+            assert_ne!(shard, Some(net_shards[1]));
+            misses.fetch_add(1, Ordering::Relaxed);
+        }))
+        .unwrap();
+        assert_eq!(found.len(), 1);
+        let found = &found[0];
+        assert!(attempts >= 1);
+        assert!(misses.into_inner() < attempts);
+        assert_eq!(found.mnemonic, Some(mnemonic));
+        assert!(found.key_pair == key_pair);
+        assert_eq!(found.wallet_id, WalletKind::V4R2.wallet_id(TESTNET_GLOBAL_ID, 0, found.subwallet));
+
+        let address = derive_wallet_address(&key_pair, WalletKind::V4R2, 0, found.wallet_id).unwrap();
+        assert_eq!(address, found.address);
+        assert_eq!(get_shard(&net_shards, &address.to_hex()), Some(net_shards[1]));
+    }
+
+    #[test]
+    fn masterchain_search_derives_masterchain_wallets() {
+        let mut net_shards = vec![ShardIdent::masterchain()];
+        net_shards.extend(shards_for_split_depth(0, 2).unwrap());
+        let (key_pair, mnemonic) = generate_key_pair(None).unwrap();
+        let mode = SearchMode::Subwallet { key_pair, mnemonic: Some(mnemonic) };
+
+        for version in [WalletKind::V4R2, WalletKind::V5R1] {
+            let config = SearchConfig { workchain: MASTERCHAIN, ..config(version, 1) };
+            let SearchResult { found, attempts, .. } = search_wallets(&net_shards, &[(ShardIdent::masterchain(), 1)], &config, &mode, &SearchProgress::default(), None).unwrap();
+            assert_eq!(attempts, 1);
+            assert_eq!(found[0].address.workchain, MASTERCHAIN);
+            assert_eq!(found[0].wallet_id, version.wallet_id(TESTNET_GLOBAL_ID, MASTERCHAIN, 0));
+        }
+    }
+
+    #[test]
+    fn raw_key_search_keeps_the_key() {
+        let net_shards = shards_for_split_depth(0, 2).unwrap();
+        let config = config(WalletKind::V4R2, 2);
+        let SearchResult { found, .. } = search_wallets(&net_shards, &[(net_shards[3], 2)], &config, &SearchMode::RawKey, &SearchProgress::default(), None).unwrap();
+        for wallet in found {
+            assert_eq!(wallet.mnemonic, None);
+            let seed: [u8; 32] = wallet.key_pair.secret_key[..32].try_into().unwrap();
+            let address = derive_wallet_address(&key_pair_from_seed(&seed), WalletKind::V4R2, 0, wallet.wallet_id).unwrap();
+            assert_eq!(address, wallet.address);
+            assert!(net_shards[3].contains_address(&address));
+        }
+    }
+
+    #[test]
+    fn split_key_search_combines_to_the_found_wallet() {
+        let net_shards = shards_for_split_depth(0, 2).unwrap();
+        let base = BaseKey::generate();
+        let mode = SearchMode::SplitKey { public_key: base.public_key() };
+
+        let config = config(WalletKind::V5R1, 3);
+        let SearchResult { found, .. } = search_wallets(&net_shards, &[(net_shards[1], 2)], &config, &mode, &SearchProgress::default(), None).unwrap();
+        assert_eq!(found.len(), 2);
+        assert_ne!(found[0].offset, found[1].offset);
+        for wallet in found {
+            assert!(wallet.key_pair.secret_key.is_empty());
+            let secret = base.combine(wallet.offset).unwrap();
+            let key_pair = KeyPair { public_key: secret.public_key().to_vec(), secret_key: Vec::new() };
+            let address = derive_wallet_address(&key_pair, WalletKind::V5R1, 0, wallet.wallet_id).unwrap();
+            assert_eq!(address, wallet.address);
+            assert!(net_shards[1].contains_address(&address));
+        }
+
+        let mut invalid = [0u8; 32];
+        invalid[0] = 2;
+        let invalid = SearchMode::SplitKey { public_key: invalid };
+        let result = search_wallets(&net_shards, &[(net_shards[1], 1)], &config, &invalid, &SearchProgress::default(), None);
+        assert!(matches!(result, Err(Error::InvalidKey(_))));
+    }
+
+    #[test]
+    fn seeded_searches_are_reproducible() {
+        let net_shards = shards_for_split_depth(0, 2).unwrap();
+        let config = SearchConfig { seed: Some(7), ..config(WalletKind::V4R2, 1) };
+        let search = |config: &SearchConfig| search_wallets(&net_shards, &[(net_shards[2], 2)], config, &SearchMode::RawKey, &SearchProgress::default(), None).unwrap();
+        let first = search(&config);
+        let second = search(&config);
+        let addresses = |result: &SearchResult| result.found.iter().map(|wallet| wallet.address.clone()).collect::<Vec<TonAddress>>();
+        assert_eq!(addresses(&first), addresses(&second));
+        for wallet in &first.found {
+            assert!(generate_raw_key_pair_from(&mut candidate_rng(7, wallet.index)) == wallet.key_pair);
+        }
+        let other = search(&SearchConfig { seed: Some(8), ..config });
+        assert_ne!(addresses(&first), addresses(&other));
+    }
+
+    #[test]
+    fn cancelled_search_resumes_at_next_index() {
+        let net_shards = shards_for_split_depth(0, 2).unwrap();
+        let mode = SearchMode::SplitKey { public_key: BaseKey::from_seed(&[9; 32]).public_key() };
+        let target = ShardIdent::from_prefix(0, 0b0110 << 60, 4).unwrap();
+        let config = config(WalletKind::V4R2, 1);
+        let complete = search_wallets(&net_shards, &[(target, 1)], &config, &mode, &SearchProgress::default(), None).unwrap();
+        assert!(!complete.interrupted);
+        assert_eq!(complete.found[0].offset, complete.found[0].index);
+
+        let progress = SearchProgress::default();
+        let interrupted = search_wallets(&net_shards, &[(target, 1)], &config, &mode, &progress, Some(&|_, _| {
+            if progress.attempts() == 2 {
+                progress.cancel();
+            }
+        }))
+        .unwrap();
+        assert!(interrupted.interrupted);
+        assert!(interrupted.found.is_empty());
+        assert_eq!(interrupted.next_index, 2);
+
+        let resumed = search_wallets(&net_shards, &[(target, 1)], &SearchConfig { start: interrupted.next_index, ..config.clone() }, &mode, &SearchProgress::default(), None).unwrap();
+        assert_eq!(resumed.found[0].index, complete.found[0].index);
+        assert_eq!(resumed.found[0].address, complete.found[0].address);
+        assert_eq!(resumed.attempts + interrupted.attempts, complete.attempts);
+
+        // a cancelled progress stops every later search right away
+        let stopped = search_wallets(&net_shards, &[(target, 1)], &config, &mode, &progress, None).unwrap();
+        assert_eq!((stopped.attempts, stopped.next_index, stopped.interrupted), (0, 0, true));
+    }
+
+    #[test]
+    fn targets_outside_the_workchain_are_rejected() {
+        let net_shards = shards_for_split_depth(0, 2).unwrap();
+        let config = SearchConfig { workchain: MASTERCHAIN, ..config(WalletKind::V4R2, 1) };
+        let result = search_wallets(&net_shards, &[(net_shards[0], 1)], &config, &SearchMode::Mnemonic { password: None }, &SearchProgress::default(), None);
+        assert!(matches!(result, Err(Error::InvalidShard(_))));
+    }
+
+    #[test]
+    fn deeper_prefix_than_the_layout() {
+        let net_shards = shards_for_split_depth(0, 1).unwrap();
+        let (key_pair, mnemonic) = generate_key_pair(None).unwrap();
+        let mode = SearchMode::Subwallet { key_pair, mnemonic: Some(mnemonic) };
+        let target = ShardIdent::from_prefix(0, 0b101 << 61, 3).unwrap();
+
+        let config = config(WalletKind::V4R2, 2);
+        let SearchResult { found, .. } = search_wallets(&net_shards, &[(target, 1)], &config, &mode, &SearchProgress::default(), None).unwrap();
+        assert_eq!(found[0].shard, target);
+        assert!(target.contains_address(&found[0].address));
+        assert_eq!(get_shard(&net_shards, &found[0].address.to_hex()), Some(net_shards[1]));
+    }
+
+    #[test]
+    fn expected_attempts_estimates() {
+        let close = |a: f64, b: f64| (a - b).abs() / b < 1e-3;
+        assert_eq!(expected_attempts(&[], None).unwrap(), 0.0);
+        assert!(close(expected_attempts(&[(ShardIdent::full(0), 1)], None).unwrap(), 1.0));
+        // geometric: 2^n attempts per wallet
+        let shard = ShardIdent::from_prefix(0, 0, 4).unwrap();
+        assert!(close(expected_attempts(&[(shard, 1)], None).unwrap(), 16.0));
+        assert!(close(expected_attempts(&[(shard, 3)], None).unwrap(), 48.0));
+        // coupon collector: one wallet in each of 4 shards takes 4 * H(4) attempts
+        let targets: Vec<(ShardIdent, usize)> = shards_for_split_depth(0, 2).unwrap().into_iter().map(|shard| (shard, 1)).collect();
+        assert!(close(expected_attempts(&targets, None).unwrap(), 4.0 * (1.0 + 1.0 / 2.0 + 1.0 / 3.0 + 1.0 / 4.0)));
+        let deep = ShardIdent::from_prefix(0, 0, 60).unwrap();
+        assert!(close(expected_attempts(&[(deep, 1)], None).unwrap(), 2f64.powi(60)));
+
+        // a pattern multiplies the difficulty of every target, unless it overlaps the shard bits
+        let ton = VanityPattern::suffix("ton").unwrap();
+        assert!(close(expected_attempts(&[(shard, 1)], Some(&ton)).unwrap(), 16.0 * 64f64.powi(3)));
+        let tag = VanityPattern::prefix("EQA").unwrap();
+        assert!(close(expected_attempts(&[(shard, 1)], Some(&tag)).unwrap(), 16.0));
+        let other = ShardIdent::from_prefix(0, 0b11 << 62, 2).unwrap();
+        assert_eq!(expected_attempts(&[(shard, 1), (other, 1)], Some(&tag)), Some(f64::INFINITY));
+        assert_eq!(expected_attempts(&[(shard, 1)], Some(&VanityPattern::regex("ton").unwrap())), None);
+    }
+
+    #[test]
+    fn expected_attempts_of_large_counts() {
+        // far beyond the count where e^-lambda underflows, the wait stays close to count / p
+        let shard = ShardIdent::from_prefix(0, 0b01 << 62, 2).unwrap();
+        let expected = expected_attempts(&[(shard, 10_000)], None).unwrap();
+        assert!((expected / 40_000.0 - 1.0).abs() < 0.01, "{}", expected);
+        assert!(poisson_at_least(1000.0, 900) > 0.99);
+        assert!(poisson_at_least(1000.0, 1100) < 0.01);
+    }
+
+    #[test]
+    fn vanity_search_matches_pattern_and_shard() {
+        let net_shards = shards_for_split_depth(0, 2).unwrap();
+        let (key_pair, mnemonic) = generate_key_pair(None).unwrap();
+        let mode = SearchMode::Subwallet { key_pair, mnemonic: Some(mnemonic) };
+        let pattern = VanityPattern::contains("a").unwrap().ignore_case(true).unwrap().non_bounceable(true);
+
+        let config = SearchConfig { pattern: Some(pattern.clone()), ..config(WalletKind::V4R2, 2) };
+        let SearchResult { found, .. } = search_wallets(&net_shards, &[(net_shards[2], 2)], &config, &mode, &SearchProgress::default(), Some(&|address, _| {
+            assert!(!net_shards[2].contains_address(address) || !pattern.matches(address));
+        }))
+        .unwrap();
+        assert_eq!(found.len(), 2);
+        for wallet in &found {
+            assert!(pattern.matches(&wallet.address));
+            assert!(net_shards[2].contains_address(&wallet.address));
+        }
+    }
+
+    fn params(subwallet: bool, targets: Vec<(ShardIdent, usize)>) -> SearchParams {
+        SearchParams {
+            wallet_version: WalletKind::V4R2.to_string(),
+            global_id: TESTNET_GLOBAL_ID,
+            workchain: 0,
+            targets,
+            pattern: None,
+            key_format: KeyFormat::Raw,
+            password_protected: false,
+            subwallet,
+            split_key: None,
+            seed: None,
+        }
+    }
+
+    #[test]
+    fn checkpoints_keep_the_state_to_resume() {
+        let net_shards = shards_for_split_depth(0, 2).unwrap();
+        let targets = vec![(net_shards[0], 2), (net_shards[1], 1)];
+        let wallet = |target: ShardIdent, index: u64| CheckpointWallet { target, index, wallet: format!("wallet {}", index) };
+        let found = vec![wallet(net_shards[0], 3), wallet(net_shards[1], 12)];
+
+        // random keys are never tried again, every wallet is kept
+        let random = Checkpoint::new(params(false, targets.clone()), &SearchMode::RawKey, 0, 20, 1.5, found.clone());
+        assert_eq!(random.found.len(), 2);
+        assert_eq!(random.remaining(&targets), [(net_shards[0], 1), (net_shards[1], 0)]);
+        assert!(random.subwallet_key(None).unwrap().is_none());
+
+        // a subwallet search keeps its key and finds the wallets past the next index again
+        let key_pair = key_pair_from_seed(&[5; 32]);
+        let mode = SearchMode::Subwallet { key_pair: key_pair.clone(), mnemonic: None };
+        let subwallet = Checkpoint::new(params(true, targets.clone()), &mode, 10, 10, 1.5, found);
+        assert_eq!(subwallet.found.len(), 1);
+        assert_eq!(subwallet.remaining(&targets), [(net_shards[0], 1), (net_shards[1], 1)]);
+        assert!(subwallet.mnemonic.is_none());
+        assert!(subwallet.subwallet_key(None).unwrap().unwrap().0 == key_pair);
+
+        let path = std::env::temp_dir().join(format!("ton-shard-master-checkpoint-{}.json", std::process::id()));
+        subwallet.write(&path, None).unwrap();
+        let read = Checkpoint::<String>::read(&path, None).unwrap();
+        assert_eq!(read.search, subwallet.search);
+        assert_eq!((read.next_index, read.found[0].target, read.found[0].wallet.as_str()), (10, net_shards[0], "wallet 3"));
+        assert!(read.subwallet_key(None).unwrap().unwrap().0 == key_pair);
+        #[cfg(unix)]
+        assert_eq!(std::os::unix::fs::PermissionsExt::mode(&std::fs::metadata(&path).unwrap().permissions()) & 0o777, 0o600);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn checkpoints_are_sealed_with_a_password() {
+        let key_pair = key_pair_from_seed(&[5; 32]);
+        let mode = SearchMode::Subwallet { key_pair: key_pair.clone(), mnemonic: None };
+        let checkpoint = Checkpoint::<String>::new(params(true, Vec::new()), &mode, 10, 10, 1.5, Vec::new());
+        let path = std::env::temp_dir().join(format!("ton-shard-master-sealed-{}.json", std::process::id()));
+        // a world readable file left at the path is replaced
+        std::fs::write(&path, "{}").unwrap();
+        checkpoint.write(&path, Some("secret")).unwrap();
+
+        let contents = std::fs::read_to_string(&path).unwrap();
+        assert!(!contents.contains(checkpoint.private_key.as_ref().unwrap()));
+        assert!(matches!(Checkpoint::<String>::read(&path, None), Err(Error::Keystore(_))));
+        assert!(Checkpoint::<String>::read(&path, Some("wrong")).is_err());
+        let read = Checkpoint::<String>::read(&path, Some("secret")).unwrap();
+        assert_eq!(read.private_key, checkpoint.private_key);
+        #[cfg(unix)]
+        assert_eq!(std::os::unix::fs::PermissionsExt::mode(&std::fs::metadata(&path).unwrap().permissions()) & 0o777, 0o600);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn key_format_names() {
+        for format in KeyFormat::ALL {
+            assert_eq!(format.name().parse::<KeyFormat>().unwrap(), format);
+            assert_eq!(serde_json::to_string(&format).unwrap(), format!("\"{}\"", format));
+        }
+        assert!("seed".parse::<KeyFormat>().is_err());
+    }
+}
@@ -0,0 +1,529 @@
+//! Shard identifiers and shard arithmetic
+//!
+//! A shard is identified by its workchain and a 64-bit shard id that stores the shard prefix in its
+//! top bits followed by a single tag bit, e.g. `0:6000000000000000` is the basechain shard of all
+//! accounts starting with the bits `01`.
+
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use tonlib::address::TonAddress;
+
+use crate::error::{Error, Result};
+
+/// Workchain id of the masterchain
+pub const MASTERCHAIN: i32 = -1;
+/// Workchain id of the basechain
+pub const BASECHAIN: i32 = 0;
+/// Shard id of a workchain that is not split
+pub const SHARD_FULL: u64 = 0x8000000000000000;
+
+/// A shard of a workchain
+///
+/// Shards order by workchain first and then from left to right within the workchain, so the shards
+/// of a sorted layout are adjacent.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct ShardIdent {
+    workchain: i32,
+    prefix: u64,
+}
+
+impl ShardIdent {
+    /// Deepest split supported by TON
+    pub const MAX_SPLIT_DEPTH: u8 = 60;
+
+    /// Shard from a tagged shard id, e.g. `0x6000000000000000`
+    pub fn new(workchain: i32, prefix: u64) -> Result<Self> {
+        if prefix == 0 || prefix.trailing_zeros() < (63 - Self::MAX_SPLIT_DEPTH as u32) {
+            return Err(Error::InvalidShard(format!("{}:{:016x}", workchain, prefix)));
+        }
+        Ok(ShardIdent { workchain, prefix })
+    }
+
+    /// The whole, unsplit workchain
+    pub fn full(workchain: i32) -> Self {
+        ShardIdent { workchain, prefix: SHARD_FULL }
+    }
+
+    /// The masterchain, which is never split
+    pub fn masterchain() -> Self {
+        Self::full(MASTERCHAIN)
+    }
+
+    /// Shard of the accounts starting with the top `len` bits of `bits`, the remaining bits are ignored
+    pub fn from_prefix(workchain: i32, bits: u64, len: u8) -> Result<Self> {
+        if len > Self::MAX_SPLIT_DEPTH {
+            return Err(Error::InvalidShard(format!("prefix of {} bits is deeper than {}", len, Self::MAX_SPLIT_DEPTH)));
+        }
+        let tag = 1u64 << (63 - len);
+        let mask = !(tag | (tag - 1));
+        Ok(ShardIdent { workchain, prefix: (bits & mask) | tag })
+    }
+
+    /// Parse a bare hex shard id, with or without `0x`
+    pub fn from_hex(workchain: i32, hex: &str) -> Result<Self> {
+        let prefix = u64::from_str_radix(hex.trim().trim_start_matches("0x"), 16)
+            .map_err(|_| Error::InvalidShard(hex.to_string()))?;
+        Self::new(workchain, prefix)
+    }
+
+    /// Workchain of the shard
+    pub fn workchain(&self) -> i32 {
+        self.workchain
+    }
+
+    /// Tagged shard id
+    pub fn prefix(&self) -> u64 {
+        self.prefix
+    }
+
+    /// Number of leading bits fixed by the shard
+    pub fn prefix_len(&self) -> u8 {
+        (63 - self.prefix.trailing_zeros()) as u8
+    }
+
+    /// Leading account id bits fixed by the shard, e.g. `01`, empty for a full workchain
+    pub fn binary_prefix(&self) -> String {
+        (0..self.prefix_len()).map(|bit| if self.prefix >> (63 - bit) & 1 == 1 { '1' } else { '0' }).collect()
+    }
+
+    /// Share of the account ids of the workchain covered by the shard
+    pub fn keyspace_fraction(&self) -> f64 {
+        0.5f64.powi(self.prefix_len() as i32)
+    }
+
+    /// Whether the shard covers the whole workchain
+    pub fn is_full(&self) -> bool {
+        self.prefix == SHARD_FULL
+    }
+
+    /// Check if the shard contains accounts whose id starts with the 64 bits `account_prefix`
+    pub fn contains(&self, account_prefix: u64) -> bool {
+        let mask = !(self.tag() | (self.tag() - 1));
+        (self.prefix ^ account_prefix) & mask == 0
+    }
+
+    /// Shard of the given depth containing the account
+    pub fn of_address(address: &TonAddress, len: u8) -> Result<Self> {
+        Self::from_prefix(address.workchain, account_prefix(address), len)
+    }
+
+    /// Check if the shard contains the given account
+    pub fn contains_address(&self, address: &TonAddress) -> bool {
+        self.workchain == address.workchain && self.contains(account_prefix(address))
+    }
+
+    /// Check if `other` is this shard or one of its descendants
+    pub fn is_ancestor_of(&self, other: &ShardIdent) -> bool {
+        self.workchain == other.workchain && self.prefix_len() <= other.prefix_len() && self.contains(other.prefix)
+    }
+
+    /// The shard this one was split from, `None` for a full workchain
+    pub fn parent(&self) -> Option<Self> {
+        if self.is_full() {
+            return None;
+        }
+        let tag = self.tag();
+        Some(ShardIdent { workchain: self.workchain, prefix: (self.prefix - tag) | (tag << 1) })
+    }
+
+    /// The two halves of the shard, `None` at the maximal split depth
+    pub fn children(&self) -> Option<[Self; 2]> {
+        if self.prefix_len() >= Self::MAX_SPLIT_DEPTH {
+            return None;
+        }
+        let child_tag = self.tag() >> 1;
+        Some([
+            ShardIdent { workchain: self.workchain, prefix: self.prefix - child_tag },
+            ShardIdent { workchain: self.workchain, prefix: self.prefix + child_tag },
+        ])
+    }
+
+    /// The other half of the parent shard, `None` for a full workchain
+    pub fn sibling(&self) -> Option<Self> {
+        if self.is_full() {
+            return None;
+        }
+        Some(ShardIdent { workchain: self.workchain, prefix: self.prefix ^ (self.tag() << 1) })
+    }
+
+    /// The lowest set bit, marking the end of the prefix
+    fn tag(&self) -> u64 {
+        self.prefix & self.prefix.wrapping_neg()
+    }
+}
+
+impl Display for ShardIdent {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{:016x}", self.workchain, self.prefix)
+    }
+}
+
+impl FromStr for ShardIdent {
+    type Err = Error;
+
+    /// Parse `workchain:shard`, a bare hex shard belongs to the basechain
+    fn from_str(s: &str) -> Result<Self> {
+        parse_shard(s, BASECHAIN)
+    }
+}
+
+/// Serialized as `workchain:shard`
+impl Serialize for ShardIdent {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for ShardIdent {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        String::deserialize(deserializer)?.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+/// Top 64 bits of the account id of an address
+pub fn account_prefix(address: &TonAddress) -> u64 {
+    u64::from_be_bytes(address.hash_part[0..8].try_into().unwrap())
+}
+
+/// Validate shard input against predefined options
+pub fn validate_shard(net_shards: &[ShardIdent], shard: ShardIdent) -> Result<()> {
+    if net_shards.contains(&shard) {
+        Ok(())
+    } else {
+        Err(Error::UnknownShard {
+            shard: shard.to_string(),
+            available: net_shards.iter().map(|shard| shard.to_string()).collect::<Vec<String>>().join(", "),
+        })
+    }
+}
+
+/// Parse a shard given as `workchain:shard` or as a bare hex shard id of `default_workchain`
+pub fn parse_shard(shard: &str, default_workchain: i32) -> Result<ShardIdent> {
+    let (workchain, id) = match shard.trim().split_once(':') {
+        Some((workchain, id)) => (workchain.parse::<i32>().map_err(|_| Error::InvalidShard(shard.to_string()))?, id),
+        None => (default_workchain, shard.trim()),
+    };
+    ShardIdent::from_hex(workchain, id)
+}
+
+/// Parse a shard given as its binary prefix, e.g. `0110`
+pub fn parse_prefix(workchain: i32, bits: &str) -> Result<ShardIdent> {
+    let bits = bits.trim().trim_start_matches("0b");
+    if bits.len() > ShardIdent::MAX_SPLIT_DEPTH as usize || bits.chars().any(|bit| bit != '0' && bit != '1') {
+        return Err(Error::InvalidShard(format!("invalid prefix {:?}, expected up to {} binary digits", bits, ShardIdent::MAX_SPLIT_DEPTH)));
+    }
+    let prefix = bits
+        .chars()
+        .enumerate()
+        .filter(|&(_, bit)| bit == '1')
+        .fold(0u64, |prefix, (i, _)| prefix | 1 << (63 - i));
+    ShardIdent::from_prefix(workchain, prefix, bits.len() as u8)
+}
+
+/// Parse a user supplied shard layout, shards without a workchain belong to the basechain
+pub fn parse_shards(shards: &[String]) -> Result<Vec<ShardIdent>> {
+    shards.iter().map(|shard| shard.parse()).collect()
+}
+
+/// Build the shard layout of a workchain evenly split to `depth`
+///
+/// Fails beyond [`ShardIdent::MAX_SPLIT_DEPTH`]. The layout has `2^depth` shards, so only small
+/// depths are practical.
+pub fn shards_for_split_depth(workchain: i32, depth: u8) -> Result<Vec<ShardIdent>> {
+    if depth > ShardIdent::MAX_SPLIT_DEPTH {
+        return Err(Error::InvalidShard(format!("split depth {} is deeper than {}", depth, ShardIdent::MAX_SPLIT_DEPTH)));
+    }
+    (0..1u64 << depth)
+        .map(|prefix| ShardIdent::from_prefix(workchain, prefix.checked_shl(64 - depth as u32).unwrap_or(0), depth))
+        .collect()
+}
+
+/// Extract the workchain and the top 64 bits from a raw `workchain:account_id` address
+///
+/// Account ids without a workchain are taken as basechain accounts.
+pub fn extract_top64(account_id: &str) -> Option<(i32, u64)> {
+    let (workchain, hex_part) = match account_id.split_once(':') {
+        Some((workchain, hex_part)) => (workchain.parse::<i32>().ok()?, hex_part),
+        None => (BASECHAIN, account_id),
+    };
+    let bytes = hex::decode(hex_part).ok()?;
+
+    // Ensure we have exactly 32 bytes (256 bits)
+    if bytes.len() != 32 {
+        return None;
+    }
+
+    // Extract the first 8 bytes (top 64 bits) as a u64
+    Some((workchain, u64::from_be_bytes(bytes[0..8].try_into().unwrap())))
+}
+
+/// Get the shard for a given raw address
+pub fn get_shard(net_shards: &[ShardIdent], account_id: &str) -> Option<ShardIdent> {
+    let (workchain, top64) = extract_top64(account_id)?;
+    net_shards
+        .iter()
+        .copied()
+        .find(|shard| shard.workchain() == workchain && shard.contains(top64))
+}
+
+/// ASCII tree of the splits of every workchain of the layout, leaves described by `describe`
+pub fn split_tree(shards: &[ShardIdent], describe: impl Fn(ShardIdent) -> String) -> Vec<String> {
+    let mut workchains: Vec<i32> = shards.iter().map(|shard| shard.workchain()).collect();
+    workchains.sort();
+    workchains.dedup();
+    let mut lines = Vec::new();
+    for workchain in workchains {
+        split_tree_lines(ShardIdent::full(workchain), shards, &describe, "", "", &mut lines);
+    }
+    lines
+}
+
+/// Lines of `shard` and its splits, `lead` starts the line of the shard and `indent` the lines below
+fn split_tree_lines(shard: ShardIdent, shards: &[ShardIdent], describe: &dyn Fn(ShardIdent) -> String, lead: &str, indent: &str, lines: &mut Vec<String>) {
+    let label = if shard.is_full() { format!("workchain {}", shard.workchain()) } else { shard.binary_prefix() };
+    if shards.contains(&shard) {
+        lines.push(format!("{}{}  {}", lead, label, describe(shard)));
+        return;
+    }
+    match shard.children().filter(|_| shards.iter().any(|other| shard.is_ancestor_of(other))) {
+        Some([left, right]) => {
+            lines.push(format!("{}{}", lead, label));
+            split_tree_lines(left, shards, describe, &format!("{}├── ", indent), &format!("{}│   ", indent), lines);
+            split_tree_lines(right, shards, describe, &format!("{}└── ", indent), &format!("{}    ", indent), lines);
+        }
+        None => lines.push(format!("{}{}  (not in the layout)", lead, label)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    fn basechain(prefix: u64) -> ShardIdent {
+        ShardIdent::new(BASECHAIN, prefix).unwrap()
+    }
+
+    fn shards() -> Vec<ShardIdent> {
+        [0x2000000000000000, 0x6000000000000000, 0xA000000000000000, 0xE000000000000000].map(basechain).to_vec()
+    }
+
+    #[test]
+    fn it_works() {
+
+        let net_shards = shards();
+
+        let addresses_shard = [
+            ("0:af78316b56ee5f7e88f3558ad3b5ebbafd49304249e48dd33c9f27e63b7c8fe7",0xa000000000000000),
+            ("0:80fa1ebdd70277ca902d52cb2007cf910ca572b80f7c186fbb86e116cf4c66ba",0xa000000000000000),
+            ("0:923150e0c668cb309dc3d43449be197e17f5095378260e7715e278eaa80941ab",0xa000000000000000),
+            ("0:684c17d1138bcd4355aa88cc30dacba8cda4d8f3de4392cb5a7f4bec030190af",0x6000000000000000),
+            ("0:51cca3ff74207b3ed8f075740b126c320e795ec4f19f70b80d9cf919fc292594",0x6000000000000000),
+            ("0:b19a8a1821d01279aeb98e84a2ed002e4a30633264702b1059cebe73100d6b95",0xa000000000000000),
+        ];
+
+        for (account_id, expect_shard) in addresses_shard {
+            let got_shard = get_shard(&net_shards, account_id);
+            assert_eq!(got_shard, Some(basechain(expect_shard)), "shard must be equal, but got: {:?}, expect: {:x?}, address: {:?}", got_shard, expect_shard, account_id);
+        }
+    }
+
+    #[test]
+    fn shard_prefix_lengths() {
+        assert_eq!(basechain(0x8000000000000000).prefix_len(), 0);
+        assert_eq!(basechain(0x6000000000000000).prefix_len(), 2);
+        assert_eq!(basechain(0xa800000000000000).prefix_len(), 4);
+        assert_eq!(basechain(0x0000000000000010).prefix_len(), 59);
+        assert_eq!(basechain(0x8000000000000000).keyspace_fraction(), 1.0);
+        assert_eq!(basechain(0x6000000000000000).keyspace_fraction(), 0.25);
+        assert_eq!(basechain(0xa800000000000000).binary_prefix(), "1010");
+    }
+
+    #[test]
+    fn invalid_shard_ids() {
+        assert!(ShardIdent::new(BASECHAIN, 0).is_err());
+        // deeper than the maximal split depth
+        assert!(ShardIdent::new(BASECHAIN, 1).is_err());
+        assert!(ShardIdent::from_prefix(BASECHAIN, 0, 61).is_err());
+        assert_eq!(ShardIdent::from_prefix(BASECHAIN, u64::MAX, 60).unwrap().prefix(), 0xfffffffffffffff8);
+        assert!(ShardIdent::from_prefix(BASECHAIN, 0, 60).unwrap().children().is_none());
+    }
+
+    #[test]
+    fn split_depth_layout() {
+        assert_eq!(shards_for_split_depth(0, 0).unwrap(), vec![ShardIdent::full(BASECHAIN)]);
+        assert_eq!(shards_for_split_depth(0, 2).unwrap(), shards());
+        assert!(matches!(shards_for_split_depth(0, 61), Err(Error::InvalidShard(_))));
+        assert!(shards_for_split_depth(0, 255).is_err());
+
+        let net_shards = shards_for_split_depth(0, 4).unwrap();
+        assert_eq!(net_shards.len(), 16);
+        assert_eq!(get_shard(&net_shards, "0:af78316b56ee5f7e88f3558ad3b5ebbafd49304249e48dd33c9f27e63b7c8fe7"), Some(basechain(0xa800000000000000)));
+        assert_eq!(get_shard(&shards_for_split_depth(0, 0).unwrap(), "0:af78316b56ee5f7e88f3558ad3b5ebbafd49304249e48dd33c9f27e63b7c8fe7"), Some(ShardIdent::full(BASECHAIN)));
+    }
+
+    #[test]
+    fn parse_shard_layout() {
+        let layout = ["2000000000000000", "0x6000000000000000", "0:A000000000000000", "0:e000000000000000"].map(String::from);
+        assert_eq!(parse_shards(&layout).unwrap(), shards());
+        assert_eq!(parse_shard("-1:8000000000000000", BASECHAIN).unwrap(), ShardIdent::masterchain());
+        assert_eq!(parse_shard("8000000000000000", MASTERCHAIN).unwrap(), ShardIdent::masterchain());
+        assert!(parse_shards(&["0".to_string()]).is_err());
+        assert!(parse_shards(&["zz".to_string()]).is_err());
+        assert!(parse_shards(&["x:8000000000000000".to_string()]).is_err());
+    }
+
+    #[test]
+    fn parse_binary_prefix() {
+        assert_eq!(parse_prefix(BASECHAIN, "").unwrap(), ShardIdent::full(BASECHAIN));
+        assert_eq!(parse_prefix(BASECHAIN, "01").unwrap(), basechain(0x6000000000000000));
+        assert_eq!(parse_prefix(MASTERCHAIN, "0b1010").unwrap(), ShardIdent::new(MASTERCHAIN, 0xa800000000000000).unwrap());
+        assert_eq!(parse_prefix(BASECHAIN, &"1".repeat(60)).unwrap().prefix_len(), 60);
+        assert!(parse_prefix(BASECHAIN, &"1".repeat(61)).is_err());
+        assert!(parse_prefix(BASECHAIN, "012").is_err());
+    }
+
+    #[test]
+    fn shards_are_workchain_aware() {
+        let mut net_shards = vec![ShardIdent::masterchain()];
+        net_shards.extend(shards());
+        let account = "af78316b56ee5f7e88f3558ad3b5ebbafd49304249e48dd33c9f27e63b7c8fe7";
+        assert_eq!(get_shard(&net_shards, &format!("-1:{}", account)), Some(ShardIdent::masterchain()));
+        assert_eq!(get_shard(&net_shards, &format!("0:{}", account)), Some(basechain(0xa000000000000000)));
+        assert_eq!(get_shard(&net_shards, account), Some(basechain(0xa000000000000000)));
+        assert_eq!(get_shard(&net_shards, &format!("7:{}", account)), None);
+        assert_eq!(ShardIdent::masterchain().to_string(), "-1:8000000000000000");
+    }
+
+    #[test]
+    fn validate_against_layout() {
+        let net_shards = shards();
+        assert!(validate_shard(&net_shards, basechain(0x6000000000000000)).is_ok());
+        assert!(validate_shard(&net_shards, ShardIdent::new(MASTERCHAIN, 0x6000000000000000).unwrap()).is_err());
+        assert!(matches!(validate_shard(&net_shards, basechain(0x4000000000000000)), Err(Error::UnknownShard { .. })));
+    }
+
+    #[test]
+    fn shard_of_address() {
+        let address = TonAddress::from_str("0:af78316b56ee5f7e88f3558ad3b5ebbafd49304249e48dd33c9f27e63b7c8fe7").unwrap();
+        assert_eq!(account_prefix(&address), 0xaf78316b56ee5f7e);
+        assert_eq!(ShardIdent::of_address(&address, 0).unwrap(), ShardIdent::full(BASECHAIN));
+        assert_eq!(ShardIdent::of_address(&address, 4).unwrap(), basechain(0xa800000000000000));
+        assert_eq!(ShardIdent::of_address(&address, 8).unwrap(), basechain(0xaf80000000000000));
+        for len in 0..=60 {
+            assert!(ShardIdent::of_address(&address, len).unwrap().contains_address(&address));
+        }
+        let masterchain = TonAddress::new(MASTERCHAIN, &address.hash_part);
+        assert!(!ShardIdent::of_address(&address, 4).unwrap().contains_address(&masterchain));
+    }
+
+    #[test]
+    fn tree_navigation() {
+        let shard = basechain(0x6000000000000000);
+        assert_eq!(shard.parent(), Some(basechain(0x4000000000000000)));
+        assert_eq!(shard.sibling(), Some(basechain(0x2000000000000000)));
+        assert_eq!(shard.children(), Some([basechain(0x5000000000000000), basechain(0x7000000000000000)]));
+        assert_eq!(ShardIdent::full(BASECHAIN).parent(), None);
+        assert_eq!(ShardIdent::full(BASECHAIN).sibling(), None);
+        assert!(ShardIdent::full(BASECHAIN).is_ancestor_of(&shard));
+        assert!(shard.is_ancestor_of(&shard));
+        assert!(!shard.is_ancestor_of(&ShardIdent::full(BASECHAIN)));
+        assert!(!ShardIdent::masterchain().is_ancestor_of(&shard));
+    }
+
+    /// Every shard down to `depth`, exhaustively
+    fn all_shards(depth: u8) -> Vec<ShardIdent> {
+        (0..=depth).flat_map(|depth| shards_for_split_depth(BASECHAIN, depth).unwrap()).collect()
+    }
+
+    #[test]
+    fn exhaustive_tree_laws() {
+        let shards = all_shards(8);
+        for shard in &shards {
+            if let Some([left, right]) = shard.children() {
+                assert_eq!(left.parent(), Some(*shard));
+                assert_eq!(right.parent(), Some(*shard));
+                assert_eq!(left.sibling(), Some(right));
+                assert_eq!(right.sibling(), Some(left));
+                assert!(left < right);
+            }
+            assert_eq!(shard.to_string().parse::<ShardIdent>().unwrap(), *shard);
+            for other in &shards {
+                // a shard is an ancestor of another iff it covers every account of it
+                let covers = (0..=255u64).map(|top8| top8 << 56).filter(|&account| other.contains(account)).all(|account| shard.contains(account));
+                assert_eq!(shard.is_ancestor_of(other), covers, "{} {}", shard, other);
+            }
+        }
+    }
+
+    #[test]
+    fn split_layout_partitions_accounts() {
+        for depth in 0..=8 {
+            let layout = shards_for_split_depth(BASECHAIN, depth).unwrap();
+            for top8 in 0..=255u64 {
+                let account = (top8 << 56) | 0x00ab_cdef_0123_4567;
+                assert_eq!(layout.iter().filter(|shard| shard.contains(account)).count(), 1);
+            }
+        }
+    }
+
+    proptest! {
+        #[test]
+        fn prefix_roundtrip(workchain in -1i32..=1, bits in any::<u64>(), len in 0u8..=60) {
+            let shard = ShardIdent::from_prefix(workchain, bits, len).unwrap();
+            prop_assert_eq!(shard.prefix_len(), len);
+            prop_assert!(shard.contains(bits));
+            prop_assert_eq!(ShardIdent::new(workchain, shard.prefix()).unwrap(), shard);
+            prop_assert_eq!(parse_shard(&shard.to_string(), BASECHAIN).unwrap(), shard);
+            prop_assert_eq!(parse_prefix(workchain, &shard.binary_prefix()).unwrap(), shard);
+        }
+
+        #[test]
+        fn children_split_parent(bits in any::<u64>(), len in 0u8..60, account in any::<u64>()) {
+            let shard = ShardIdent::from_prefix(BASECHAIN, bits, len).unwrap();
+            let [left, right] = shard.children().unwrap();
+            prop_assert_eq!(left.prefix_len(), len + 1);
+            prop_assert_eq!(shard.contains(account), left.contains(account) || right.contains(account));
+            prop_assert!(!(left.contains(account) && right.contains(account)));
+            prop_assert!(shard.is_ancestor_of(&left) && shard.is_ancestor_of(&right));
+            prop_assert!(!left.is_ancestor_of(&right));
+        }
+
+        #[test]
+        fn ancestors_contain_descendants(bits in any::<u64>(), len in 1u8..=60) {
+            let shard = ShardIdent::from_prefix(BASECHAIN, bits, len).unwrap();
+            let mut ancestor = shard;
+            while let Some(parent) = ancestor.parent() {
+                prop_assert!(parent.is_ancestor_of(&shard));
+                prop_assert!(parent.contains(bits));
+                prop_assert_eq!(parent.prefix_len() + 1, ancestor.prefix_len());
+                ancestor = parent;
+            }
+            prop_assert!(ancestor.is_full());
+        }
+    }
+
+    #[test]
+    fn split_tree_follows_uneven_splits() {
+        let mut net_shards = vec![ShardIdent::masterchain(), "0:c000000000000000".parse().unwrap()];
+        net_shards.extend(ShardIdent::from_prefix(BASECHAIN, 0, 1).unwrap().children().unwrap());
+        assert_eq!(
+            split_tree(&net_shards, |shard| shard.to_string()),
+            [
+                "workchain -1  -1:8000000000000000",
+                "workchain 0",
+                "├── 0",
+                "│   ├── 00  0:2000000000000000",
+                "│   └── 01  0:6000000000000000",
+                "└── 1  0:c000000000000000",
+            ]
+        );
+
+        // gaps of a layout given by hand are shown
+        let gap = split_tree(&[ShardIdent::from_prefix(BASECHAIN, 0, 1).unwrap()], |shard| shard.to_string());
+        assert_eq!(gap, ["workchain 0", "├── 0  0:4000000000000000", "└── 1  (not in the layout)"]);
+        assert!(split_tree(&[], |shard| shard.to_string()).is_empty());
+    }
+}
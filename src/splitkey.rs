@@ -0,0 +1,319 @@
+//! Split-key search: outsource the search without handing out the secret
+//!
+//! The owner keeps a base key pair `(a, A = aB)` and only hands out `A`. The searcher walks the
+//! public keys `A + 8tB` for offsets `t = 0, 1, 2, ...` until the wallet of one of them lands in the
+//! target shard, and reports `t`. The owner adds `8t` to the secret scalar, `a + 8t` is the secret of
+//! `A + 8tB`. Knowing `A` and `t` gives the searcher nothing about `a + 8t`.
+//!
+//! Offsets are counted in multiples of 8 so that the combined scalar is still a clamped Ed25519
+//! scalar. It is exported as an expanded secret key, the scalar followed by a hash prefix derived
+//! from the prefix of the base key and the offset, so that no two combined keys share the nonces of
+//! their signatures. The combined secret is not a seed: wallet apps cannot import it, messages of
+//! the wallet are signed with [`CombinedWallet::external_message`] instead.
+
+use curve25519_dalek::edwards::{CompressedEdwardsY, EdwardsPoint};
+use curve25519_dalek::scalar::clamp_integer;
+use curve25519_dalek::Scalar;
+use ed25519_dalek::hazmat::{raw_sign, ExpandedSecretKey};
+use ed25519_dalek::{Signature, VerifyingKey};
+use rand::rngs::OsRng;
+use rand::RngCore;
+use sha2::{Digest, Sha512};
+use tonlib::address::TonAddress;
+use tonlib::cell::{Cell, CellBuilder};
+use tonlib::mnemonic::KeyPair;
+
+use crate::error::{Error, Result};
+use crate::wallet::{derive_wallet_address, wallet_state_init, WalletKind};
+
+/// Base key pair of a split-key search, kept by the owner
+pub struct BaseKey {
+    seed: [u8; 32],
+    expanded: [u8; 64],
+}
+
+impl BaseKey {
+    /// Base key of an Ed25519 seed
+    pub fn from_seed(seed: &[u8; 32]) -> Self {
+        let mut expanded: [u8; 64] = Sha512::digest(seed).into();
+        let scalar = clamp_integer(expanded[..32].try_into().unwrap());
+        expanded[..32].copy_from_slice(&scalar);
+        BaseKey { seed: *seed, expanded }
+    }
+
+    /// Random base key
+    pub fn generate() -> Self {
+        let mut seed = [0u8; 32];
+        OsRng.fill_bytes(&mut seed);
+        BaseKey::from_seed(&seed)
+    }
+
+    /// Secret seed, the only thing to back up
+    pub fn seed(&self) -> &[u8; 32] {
+        &self.seed
+    }
+
+    /// Public key handed to the searcher
+    pub fn public_key(&self) -> [u8; 32] {
+        SplitSecret::new(self.expanded).public_key
+    }
+
+    /// Secret key of the wallet found at `offset`
+    pub fn combine(&self, offset: u64) -> Result<SplitSecret> {
+        let mut expanded = self.expanded;
+        let mut carry = offset as u128 * 8;
+        for byte in &mut expanded[..32] {
+            carry += *byte as u128;
+            *byte = carry as u8;
+            carry >>= 8;
+        }
+        // the scalar has to keep bit 254 set and bit 255 clear to stay clamped
+        if expanded[31] & 0xc0 != 0x40 {
+            return Err(Error::InvalidKey(format!("offset {} overflows the secret scalar", offset)));
+        }
+        // the nonce of a signature is the hash of the prefix and the message, a prefix shared by
+        // two keys would leak both secrets from their signatures of the same message
+        let prefix = Sha512::new().chain_update(&self.expanded[32..]).chain_update(offset.to_le_bytes()).finalize();
+        expanded[32..].copy_from_slice(&prefix[..32]);
+        Ok(SplitSecret::new(expanded))
+    }
+}
+
+/// Combined secret key of a split-key search
+pub struct SplitSecret {
+    expanded: [u8; 64],
+    public_key: [u8; 32],
+}
+
+impl SplitSecret {
+    fn new(expanded: [u8; 64]) -> Self {
+        let scalar = ExpandedSecretKey::from_bytes(&expanded).scalar;
+        SplitSecret { expanded, public_key: EdwardsPoint::mul_base(&scalar).compress().to_bytes() }
+    }
+
+    /// Public key of the wallet
+    pub fn public_key(&self) -> [u8; 32] {
+        self.public_key
+    }
+
+    /// Expanded secret key: the clamped secret scalar followed by the hash prefix of the nonces
+    pub fn to_bytes(&self) -> [u8; 64] {
+        self.expanded
+    }
+
+    /// Key pair without secret, to derive the wallet
+    fn key_pair(&self) -> KeyPair {
+        KeyPair { public_key: self.public_key.to_vec(), secret_key: Vec::new() }
+    }
+
+    /// Ed25519 signature of `message`, valid for [`SplitSecret::public_key`]
+    pub fn sign(&self, message: &[u8]) -> Signature {
+        let verifying_key = VerifyingKey::from_bytes(&self.public_key).expect("a multiple of the base point is a valid key");
+        raw_sign::<Sha512>(&ExpandedSecretKey::from_bytes(&self.expanded), message, &verifying_key)
+    }
+}
+
+/// Wallet of a combined secret key
+pub struct CombinedWallet {
+    secret: SplitSecret,
+    version: WalletKind,
+    wallet_id: i32,
+    address: TonAddress,
+}
+
+impl CombinedWallet {
+    /// Combine the base secret `seed` with the `offset` found by a split-key search for `version`
+    pub fn new(seed: &[u8; 32], offset: u64, version: WalletKind, workchain: i32, global_id: i32) -> Result<Self> {
+        let secret = BaseKey::from_seed(seed).combine(offset)?;
+        let wallet_id = version.wallet_id(global_id, workchain, 0);
+        let address = derive_wallet_address(&secret.key_pair(), version, workchain, wallet_id)?;
+        Ok(CombinedWallet { secret, version, wallet_id, address })
+    }
+
+    /// Combined secret key
+    pub fn secret(&self) -> &SplitSecret {
+        &self.secret
+    }
+
+    /// Wallet id of the wallet
+    pub fn wallet_id(&self) -> i32 {
+        self.wallet_id
+    }
+
+    /// Address of the wallet
+    pub fn address(&self) -> &TonAddress {
+        &self.address
+    }
+
+    /// Check that the wallet is the one reported by the search
+    pub fn check_address(&self, expected: &TonAddress) -> Result<()> {
+        if *expected != self.address {
+            return Err(Error::InvalidKey(format!(
+                "the combined key belongs to {}, not {}; check the secret key, offset, wallet version, workchain and network",
+                self.address, expected
+            )));
+        }
+        Ok(())
+    }
+
+    /// External message to the wallet carrying `body`, signed with the combined key
+    ///
+    /// `body` is the unsigned body of the wallet contract, e.g. from
+    /// `TonWallet::create_external_body`. The signature of its hash precedes it, or follows it for
+    /// wallet v5, and is verified against the public key of the wallet state before the message is
+    /// built. With `deploy` the message carries the state init of the wallet.
+    pub fn external_message(&self, body: &Cell, deploy: bool) -> Result<Cell> {
+        let key_pair = self.secret.key_pair();
+        let hash = body.cell_hash();
+        let signature = self.secret.sign(&hash);
+        VerifyingKey::from_bytes(key_pair.public_key.as_slice().try_into().expect("public keys are 32 bytes"))
+            .and_then(|key| key.verify_strict(&hash, &signature))
+            .map_err(|err| Error::InvalidKey(format!("the signature does not match the wallet key: {}", err)))?;
+
+        let mut signed = CellBuilder::new();
+        match self.version {
+            WalletKind::V5R1 => signed.store_cell(body)?.store_slice(&signature.to_bytes())?,
+            _ => signed.store_slice(&signature.to_bytes())?.store_cell(body)?,
+        };
+        let mut message = CellBuilder::new();
+        message
+            .store_u8(2, 2)? // ext_in_msg_info
+            .store_address(&TonAddress::NULL)? // src
+            .store_address(&self.address)? // dest
+            .store_u8(4, 0)?; // import fee, zero coins
+        if deploy {
+            message.store_bit(true)?.store_bit(true)?.store_child(wallet_state_init(&key_pair, self.version, self.wallet_id)?)?;
+        } else {
+            message.store_bit(false)?;
+        }
+        message.store_bit(true)?.store_child(signed.build()?)?;
+        Ok(message.build()?)
+    }
+}
+
+/// Public key found by the searcher at `offset` from `base`
+pub fn offset_public_key(base: &[u8; 32], offset: u64) -> Result<[u8; 32]> {
+    Ok((decompress(base)? + offset_point(offset)).compress().to_bytes())
+}
+
+/// `8 * offset * B`
+fn offset_point(offset: u64) -> EdwardsPoint {
+    EdwardsPoint::mul_base(&Scalar::from(offset)).mul_by_cofactor()
+}
+
+/// Point of an Ed25519 public key
+pub(crate) fn decompress(public_key: &[u8; 32]) -> Result<EdwardsPoint> {
+    CompressedEdwardsY(*public_key)
+        .decompress()
+        .ok_or_else(|| Error::InvalidKey(format!("{} is not a point of the curve", hex::encode(public_key))))
+}
+
+/// Public keys at offsets `start, start + stride, ...` from a base key, one point addition each
+pub(crate) struct OffsetWalk {
+    point: EdwardsPoint,
+    step: EdwardsPoint,
+    offset: u64,
+    stride: u64,
+}
+
+impl OffsetWalk {
+    pub(crate) fn new(base: EdwardsPoint, start: u64, stride: u64) -> Self {
+        OffsetWalk { point: base + offset_point(start), step: offset_point(stride), offset: start, stride }
+    }
+
+    /// Next offset with its public key, as a key pair without secret
+    pub(crate) fn next_key(&mut self) -> (u64, KeyPair) {
+        let current = (self.offset, KeyPair { public_key: self.point.compress().to_bytes().to_vec(), secret_key: Vec::new() });
+        self.point += self.step;
+        self.offset = self.offset.wrapping_add(self.stride);
+        current
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::shard::BASECHAIN;
+    use crate::wallet::key_pair_from_seed;
+
+    #[test]
+    fn combined_key_matches_the_searched_wallet() {
+        let base = BaseKey::generate();
+        let mut walk = OffsetWalk::new(decompress(&base.public_key()).unwrap(), 5, 3);
+        walk.next_key();
+        let (offset, searched) = walk.next_key();
+        assert_eq!(offset, 8);
+
+        let secret = base.combine(offset).unwrap();
+        assert_eq!(searched.public_key, secret.public_key().to_vec());
+        assert_eq!(offset_public_key(&base.public_key(), offset).unwrap(), secret.public_key());
+        let wallet_id = WalletKind::V4R2.wallet_id(0, 0, 0);
+        assert_eq!(
+            derive_wallet_address(&searched, WalletKind::V4R2, 0, wallet_id).unwrap(),
+            derive_wallet_address(&KeyPair { public_key: secret.public_key().to_vec(), secret_key: Vec::new() }, WalletKind::V4R2, 0, wallet_id).unwrap()
+        );
+
+        let message = b"transfer";
+        let signature = secret.sign(message);
+        VerifyingKey::from_bytes(&secret.public_key()).unwrap().verify_strict(message, &signature).unwrap();
+        assert!(VerifyingKey::from_bytes(&base.public_key()).unwrap().verify_strict(message, &signature).is_err());
+        assert_eq!(clamp_integer(secret.to_bytes()[..32].try_into().unwrap()), secret.to_bytes()[..32]);
+    }
+
+    #[test]
+    fn combined_keys_do_not_share_nonces() {
+        let base = BaseKey::generate();
+        let message = b"transfer";
+        let (first, second) = (base.combine(1).unwrap().sign(message), base.combine(2).unwrap().sign(message));
+        assert_ne!(first.r_bytes(), second.r_bytes());
+        assert_eq!(base.combine(1).unwrap().sign(message), first);
+    }
+
+    #[test]
+    fn combined_wallet_is_checked_against_the_address() {
+        let seed = [42u8; 32];
+        let wallet = CombinedWallet::new(&seed, 3, WalletKind::V4R2, BASECHAIN, -3).unwrap();
+        assert_eq!(wallet.secret().public_key(), BaseKey::from_seed(&seed).combine(3).unwrap().public_key());
+        wallet.check_address(wallet.address()).unwrap();
+        let other = CombinedWallet::new(&seed, 4, WalletKind::V4R2, BASECHAIN, -3).unwrap();
+        assert!(matches!(other.check_address(wallet.address()), Err(Error::InvalidKey(_))));
+    }
+
+    #[test]
+    fn external_messages_are_signed_for_the_wallet_key() {
+        let seed = [42u8; 32];
+        for version in [WalletKind::V4R2, WalletKind::V5R1] {
+            let wallet = CombinedWallet::new(&seed, 3, version, BASECHAIN, -3).unwrap();
+            let body = CellBuilder::new().store_i32(32, wallet.wallet_id()).unwrap().store_u32(32, 1_700_000_000).unwrap().store_u32(32, 0).unwrap().build().unwrap();
+            let message = wallet.external_message(&body, true).unwrap();
+
+            // the state init is the one the address was derived from
+            let state_init = message.reference(0).unwrap();
+            assert_eq!(state_init.cell_hash().as_slice(), wallet.address().hash_part.as_slice());
+            let signed = message.reference(1).unwrap();
+            let bytes = signed.data();
+            let (signature, rest) = match version {
+                WalletKind::V5R1 => (&bytes[12..], &bytes[..12]),
+                _ => (&bytes[..64], &bytes[64..]),
+            };
+            assert_eq!(rest, body.data());
+            let signature = Signature::from_slice(signature).unwrap();
+            VerifyingKey::from_bytes(&wallet.secret().public_key()).unwrap().verify_strict(&body.cell_hash(), &signature).unwrap();
+
+            assert_eq!(wallet.external_message(&body, false).unwrap().references().len(), 1);
+        }
+    }
+
+    #[test]
+    fn offset_zero_is_the_base_key() {
+        let seed = [7u8; 32];
+        let base = BaseKey::from_seed(&seed);
+        assert_eq!(base.public_key().to_vec(), key_pair_from_seed(&seed).public_key);
+        assert_eq!(base.combine(0).unwrap().public_key(), base.public_key());
+        assert!(base.combine(u64::MAX).is_ok());
+        // y = 2 has no matching x on the curve
+        let mut invalid = [0u8; 32];
+        invalid[0] = 2;
+        assert!(matches!(decompress(&invalid), Err(Error::InvalidKey(_))));
+    }
+}
@@ -0,0 +1,270 @@
+//! Vanity patterns on the user-friendly form of wallet addresses
+//!
+//! User-friendly addresses are 36 bytes (flags, workchain, account id and a CRC16) encoded as 48
+//! base64url characters, so the first characters are fixed by the flags and the workchain and
+//! the following ones by the account id, i.e. partly by the shard of the wallet. The flags tell
+//! bounceable (`EQ`) from non-bounceable (`UQ`) addresses, and testnet addresses (`kQ`, `0Q`) from
+//! the production ones.
+
+use std::fmt;
+
+use regex::{Regex, RegexBuilder};
+use tonlib::address::TonAddress;
+
+use crate::error::{Error, Result};
+use crate::shard::ShardIdent;
+
+/// Length of a user-friendly address
+const ADDRESS_LEN: usize = 48;
+/// Characters of the base64url alphabet, in the order of their values
+const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789-_";
+
+/// Where a pattern has to appear in the address
+#[derive(Clone, Debug)]
+pub enum PatternKind {
+    /// The address starts with the pattern, including the `EQ`/`UQ`/`kQ`/`0Q` tag
+    Prefix(String),
+    /// The address ends with the pattern
+    Suffix(String),
+    /// The pattern appears anywhere in the address
+    Contains(String),
+    /// The address matches a regular expression
+    Regex(Regex),
+}
+
+/// Pattern searched for in the user-friendly form of wallet addresses
+#[derive(Clone, Debug)]
+pub struct VanityPattern {
+    kind: PatternKind,
+    ignore_case: bool,
+    non_bounceable: bool,
+    testnet: bool,
+}
+
+impl VanityPattern {
+    /// Address starting with `prefix`
+    pub fn prefix(prefix: &str) -> Result<Self> {
+        Self::literal(prefix).map(|prefix| Self::new(PatternKind::Prefix(prefix)))
+    }
+
+    /// Address ending with `suffix`
+    pub fn suffix(suffix: &str) -> Result<Self> {
+        Self::literal(suffix).map(|suffix| Self::new(PatternKind::Suffix(suffix)))
+    }
+
+    /// Address containing `pattern`
+    pub fn contains(pattern: &str) -> Result<Self> {
+        Self::literal(pattern).map(|pattern| Self::new(PatternKind::Contains(pattern)))
+    }
+
+    /// Address matching the regular expression `pattern`
+    pub fn regex(pattern: &str) -> Result<Self> {
+        let regex = Regex::new(pattern).map_err(|err| Error::InvalidPattern(err.to_string()))?;
+        Ok(Self::new(PatternKind::Regex(regex)))
+    }
+
+    /// Compare letters ignoring their case
+    pub fn ignore_case(mut self, ignore_case: bool) -> Result<Self> {
+        if let PatternKind::Regex(regex) = &self.kind {
+            let regex = RegexBuilder::new(regex.as_str())
+                .case_insensitive(ignore_case)
+                .build()
+                .map_err(|err| Error::InvalidPattern(err.to_string()))?;
+            self.kind = PatternKind::Regex(regex);
+        }
+        self.ignore_case = ignore_case;
+        Ok(self)
+    }
+
+    /// Match the non-bounceable (`UQ`) form instead of the bounceable (`EQ`) one
+    pub fn non_bounceable(mut self, non_bounceable: bool) -> Self {
+        self.non_bounceable = non_bounceable;
+        self
+    }
+
+    /// Match the testnet (`kQ`/`0Q`) form instead of the production one
+    pub fn testnet(mut self, testnet: bool) -> Self {
+        self.testnet = testnet;
+        self
+    }
+
+    /// The pattern and where it is matched
+    pub fn kind(&self) -> &PatternKind {
+        &self.kind
+    }
+
+    /// The address form the pattern is matched against
+    pub fn address_form(&self, address: &TonAddress) -> String {
+        address.to_base64_url_flags(self.non_bounceable, self.testnet)
+    }
+
+    /// Check if the address matches the pattern
+    pub fn matches(&self, address: &TonAddress) -> bool {
+        let form = self.address_form(address);
+        let literal_matches = |pattern: &str, matches: fn(&str, &str) -> bool| {
+            if self.ignore_case {
+                matches(&form.to_ascii_lowercase(), &pattern.to_ascii_lowercase())
+            } else {
+                matches(&form, pattern)
+            }
+        };
+        match &self.kind {
+            PatternKind::Prefix(prefix) => literal_matches(prefix, |form, prefix| form.starts_with(prefix)),
+            PatternKind::Suffix(suffix) => literal_matches(suffix, |form, suffix| form.ends_with(suffix)),
+            PatternKind::Contains(pattern) => literal_matches(pattern, |form, pattern| form.contains(pattern)),
+            PatternKind::Regex(regex) => regex.is_match(&form),
+        }
+    }
+
+    /// Probability that a random wallet of `shard` matches, `None` for regular expressions
+    ///
+    /// Exact for prefixes and suffixes. For `contains` the occurrences at different offsets are
+    /// taken as independent, which is close enough for patterns of a few characters.
+    pub fn probability(&self, shard: &ShardIdent) -> Option<f64> {
+        let at = |pattern: &str, offset: usize| -> f64 {
+            pattern
+                .bytes()
+                .enumerate()
+                .map(|(i, char)| self.char_probability(shard, offset + i, char))
+                .product()
+        };
+        let fits = |pattern: &str| pattern.len() <= ADDRESS_LEN;
+        match &self.kind {
+            PatternKind::Prefix(prefix) => Some(if fits(prefix) { at(prefix, 0) } else { 0.0 }),
+            PatternKind::Suffix(suffix) => Some(if fits(suffix) { at(suffix, ADDRESS_LEN - suffix.len()) } else { 0.0 }),
+            PatternKind::Contains(pattern) if !fits(pattern) => Some(0.0),
+            PatternKind::Contains(pattern) => {
+                let none = (0..=ADDRESS_LEN - pattern.len()).map(|offset| 1.0 - at(pattern, offset)).product::<f64>();
+                Some(1.0 - none)
+            }
+            PatternKind::Regex(_) => None,
+        }
+    }
+
+    /// Probability that the character at `position` of an address of `shard` matches `char`
+    fn char_probability(&self, shard: &ShardIdent, position: usize, char: u8) -> f64 {
+        let flags: u8 = if self.non_bounceable { 0x51 } else { 0x11 } | if self.testnet { 0x80 } else { 0 };
+        let header = u16::from_be_bytes([flags, shard.workchain() as i8 as u8]);
+        // value of a bit of the encoded address, if it is fixed by the header or the shard
+        let known = |bit: usize| -> Option<bool> {
+            match bit {
+                0..=15 => Some(header >> (15 - bit) & 1 == 1),
+                _ if bit - 16 < shard.prefix_len() as usize => Some(shard.prefix() >> (63 - (bit - 16)) & 1 == 1),
+                _ => None,
+            }
+        };
+        let possible = (0..64u8).filter(|value| {
+            (0..6).all(|i| known(position * 6 + i).is_none_or(|bit| (value >> (5 - i) & 1 == 1) == bit))
+        });
+        let (mut total, mut matching) = (0u32, 0u32);
+        for value in possible {
+            total += 1;
+            let candidate = ALPHABET[value as usize];
+            if candidate == char || (self.ignore_case && candidate.eq_ignore_ascii_case(&char)) {
+                matching += 1;
+            }
+        }
+        matching as f64 / total as f64
+    }
+
+    fn new(kind: PatternKind) -> Self {
+        VanityPattern { kind, ignore_case: false, non_bounceable: false, testnet: false }
+    }
+
+    fn literal(pattern: &str) -> Result<String> {
+        match pattern.bytes().find(|char| !ALPHABET.contains(char)) {
+            Some(char) => Err(Error::InvalidPattern(format!("{:?} is not a base64url character", char as char))),
+            None if pattern.is_empty() => Err(Error::InvalidPattern("empty pattern".to_string())),
+            None => Ok(pattern.to_string()),
+        }
+    }
+}
+
+impl fmt::Display for VanityPattern {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.kind {
+            PatternKind::Prefix(prefix) => write!(f, "prefix {}", prefix)?,
+            PatternKind::Suffix(suffix) => write!(f, "suffix {}", suffix)?,
+            PatternKind::Contains(pattern) => write!(f, "contains {}", pattern)?,
+            PatternKind::Regex(regex) => write!(f, "regex {}", regex)?,
+        }
+        write!(f, " ({}", if self.non_bounceable { "non-bounceable" } else { "bounceable" })?;
+        if self.testnet {
+            write!(f, ", testnet")?;
+        }
+        if self.ignore_case {
+            write!(f, ", ignoring case")?;
+        }
+        write!(f, ")")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::shard::{BASECHAIN, MASTERCHAIN};
+    use std::str::FromStr;
+
+    const ADDRESS: &str = "EQCDM_QGggZ3qMa_f3lRPk4_qLDnLTqdi6OkMAV2NB9r5TG3";
+
+    #[test]
+    fn literal_patterns() {
+        let address = TonAddress::from_str(ADDRESS).unwrap();
+        assert!(VanityPattern::prefix("EQCDM").unwrap().matches(&address));
+        assert!(!VanityPattern::prefix("EQCDm").unwrap().matches(&address));
+        assert!(VanityPattern::prefix("EQCDm").unwrap().ignore_case(true).unwrap().matches(&address));
+        assert!(VanityPattern::suffix("r5TG3").unwrap().matches(&address));
+        assert!(VanityPattern::contains("_qLDn").unwrap().matches(&address));
+        assert!(!VanityPattern::contains("_qLDn").unwrap().non_bounceable(true).matches(&TonAddress::from_str("0:0000000000000000000000000000000000000000000000000000000000000000").unwrap()));
+        assert!(VanityPattern::prefix("UQCDM").unwrap().non_bounceable(true).matches(&address));
+        assert!(VanityPattern::prefix("kQCDM").unwrap().testnet(true).matches(&address));
+        assert!(VanityPattern::prefix("0QCDM").unwrap().non_bounceable(true).testnet(true).matches(&address));
+        assert!(!VanityPattern::prefix("EQCDM").unwrap().testnet(true).matches(&address));
+        assert!(VanityPattern::prefix("EQ+").is_err());
+        assert!(VanityPattern::suffix("").is_err());
+        assert_eq!(VanityPattern::prefix("EQDton").unwrap().ignore_case(true).unwrap().to_string(), "prefix EQDton (bounceable, ignoring case)");
+    }
+
+    #[test]
+    fn regex_patterns() {
+        let address = TonAddress::from_str(ADDRESS).unwrap();
+        assert!(VanityPattern::regex("^EQC.*TG3$").unwrap().matches(&address));
+        assert!(!VanityPattern::regex("tg3$").unwrap().matches(&address));
+        assert!(VanityPattern::regex("tg3$").unwrap().ignore_case(true).unwrap().matches(&address));
+        assert!(VanityPattern::regex("(").is_err());
+        assert_eq!(VanityPattern::regex("x").unwrap().probability(&ShardIdent::full(BASECHAIN)), None);
+    }
+
+    #[test]
+    fn pattern_probabilities() {
+        let full = ShardIdent::full(BASECHAIN);
+        let p = |pattern: VanityPattern, shard: &ShardIdent| pattern.probability(shard).unwrap();
+        // the tag is fixed, the third character only takes the values A to D in the basechain
+        assert_eq!(p(VanityPattern::prefix("EQ").unwrap(), &full), 1.0);
+        assert_eq!(p(VanityPattern::prefix("UQ").unwrap(), &full), 0.0);
+        assert_eq!(p(VanityPattern::prefix("UQ").unwrap().non_bounceable(true), &full), 1.0);
+        assert_eq!(p(VanityPattern::prefix("kQ").unwrap().testnet(true), &full), 1.0);
+        assert_eq!(p(VanityPattern::prefix("0Q").unwrap().non_bounceable(true).testnet(true), &full), 1.0);
+        assert_eq!(p(VanityPattern::prefix("EQ").unwrap().testnet(true), &full), 0.0);
+        assert_eq!(p(VanityPattern::prefix("EQD").unwrap(), &full), 0.25);
+        assert_eq!(p(VanityPattern::prefix("EQE").unwrap(), &full), 0.0);
+        assert_eq!(p(VanityPattern::prefix("Ef8").unwrap(), &ShardIdent::masterchain()), 0.25);
+        assert_eq!(p(VanityPattern::prefix("EQDton").unwrap(), &full), 0.25 / 64f64.powi(3));
+        assert_eq!(p(VanityPattern::prefix("EQDton").unwrap().ignore_case(true).unwrap(), &full), 0.25 * 8.0 / 64f64.powi(3));
+        assert_eq!(p(VanityPattern::suffix("ton").unwrap(), &full), 1.0 / 64f64.powi(3));
+
+        // the shard fixes the top bits of the account id: `D` is the value 3, i.e. the bits 11
+        let shard = ShardIdent::from_prefix(BASECHAIN, 0b11 << 62, 2).unwrap();
+        assert_eq!(p(VanityPattern::prefix("EQD").unwrap(), &shard), 1.0);
+        assert_eq!(p(VanityPattern::prefix("EQC").unwrap(), &shard), 0.0);
+        // the next character holds the bits 2 to 7, two of them fixed by a 4 bit shard
+        let shard = ShardIdent::from_prefix(BASECHAIN, 0b1100 << 60, 4).unwrap();
+        assert_eq!(p(VanityPattern::prefix("EQDA").unwrap(), &shard), 1.0 / 16.0);
+        assert_eq!(p(VanityPattern::prefix("EQD_").unwrap(), &shard), 0.0);
+
+        let contains = p(VanityPattern::contains("ton").unwrap(), &full);
+        assert!(contains > 40.0 / 64f64.powi(3) && contains < 46.0 / 64f64.powi(3));
+        assert_eq!(p(VanityPattern::contains(&"A".repeat(49)).unwrap(), &full), 0.0);
+        assert_eq!(p(VanityPattern::prefix("Ef").unwrap(), &ShardIdent::full(MASTERCHAIN)), 1.0);
+    }
+}
@@ -0,0 +1,457 @@
+//! Key generation and wallet address derivation
+
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+use std::sync::{Arc, OnceLock};
+
+use ed25519_dalek::SigningKey;
+use rand::rngs::OsRng;
+use rand::{CryptoRng, Rng, RngCore};
+use sha2::{Digest, Sha256};
+
+use tonlib::address::TonAddress;
+use tonlib::cell::{ArcCell, BagOfCells, Cell, CellBuilder, StateInit, StateInitBuilder, TonCellError};
+use tonlib::mnemonic::KeyPair;
+use tonlib::wallet::{WalletDataHighloadV2R2, WalletDataV3, WalletVersion, DEFAULT_WALLET_ID};
+
+use crate::error::{Error, Result};
+use crate::mnemonic::{generate_mnemonic, mnemonic_to_key_pair};
+
+/// Wallet V5R1 code, not shipped with tonlib
+const WALLET_V5R1_CODE: &str = "te6ccgECFAEAAoEAART/APSkE/S88sgLAQIBIAIDAgFIBAUBAvIOAtzQINdJwSCRW49jINcLHyCCEGV4dG69IYIQc2ludL2wkl8D4IIQZXh0brqOtIAg1yEB0HTXIfpAMPpE+Cj6RDBYvZFb4O1E0IEBQdch9AWDB/QOb6ExkTDhgEDXIXB/2zzgMSDXSYECgLmRMOBw4hAPAgEgBgcCASAICQAZvl8PaiaECAoOuQ+gLAIBbgoLAgFIDA0AGa3OdqJoQCDrkOuF/8AAGa8d9qJoQBDrkOuFj8AAF7Ml+1E0HHXIdcLH4AARsmL7UTQ1woAgAR4g1wsfghBzaWduuvLgin8PAeaO8O2i7fshgwjXIgKDCNcjIIAg1yHTH9Mf0x/tRNDSANMfINMf0//XCgAK+QFAzPkQmiiUXwrbMeHywIffArNQB7Dy0IRRJbry4IVQNrry4Ib4I7vy0IgikvgA3gGkf8jKAMsfAc8Wye1UIJL4D95w2zzYEAP27aLt+wL0BCFukmwhjkwCIdc5MHCUIccAs44tAdcoIHYeQ2wg10nACPLgkyDXSsAC8uCTINcdBscSwgBSMLDy0InXTNc5MAGk6GwShAe78uCT10rAAPLgk+1V4tIAAcAAkVvg69csCBQgkXCWAdcsCBwS4lIQseMPINdKERITAJYB+kAB+kT4KPpEMFi68uCR7UTQgQFB1xj0BQSdf8jKAEAEgwf0U/Lgi44UA4MH9Fvy4Iwi1woAIW4Bs7Dy0JDiyFADzxYS9ADJ7VQAcjDXLAgkji0h8uCS0gDtRNDSAFETuvLQj1RQMJExnAGBAUDXIdcKAPLgjuLIygBYzxbJ7VST8sCN4gAQk1vbMeHXTNA=";
+
+/// Wallet contract versions supported by the generator
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum WalletKind {
+    /// Wallet v3 revision 1
+    V3R1,
+    /// Wallet v3 revision 2
+    V3R2,
+    /// Wallet v4 revision 2
+    V4R2,
+    /// Wallet v5 revision 1 (W5)
+    V5R1,
+    /// Highload wallet v1 revision 1
+    HighloadV1R1,
+    /// Highload wallet v1 revision 2
+    HighloadV1R2,
+    /// Highload wallet v2
+    HighloadV2,
+    /// Highload wallet v2 revision 1
+    HighloadV2R1,
+    /// Highload wallet v2 revision 2
+    HighloadV2R2,
+}
+
+impl WalletKind {
+    /// Every supported version
+    pub const ALL: [WalletKind; 9] = [
+        WalletKind::V3R1,
+        WalletKind::V3R2,
+        WalletKind::V4R2,
+        WalletKind::V5R1,
+        WalletKind::HighloadV1R1,
+        WalletKind::HighloadV1R2,
+        WalletKind::HighloadV2,
+        WalletKind::HighloadV2R1,
+        WalletKind::HighloadV2R2,
+    ];
+
+    /// Wallet id of the given subwallet, subwallet 0 being the default wallet
+    pub fn wallet_id(&self, global_id: i32, workchain: i32, subwallet: u32) -> i32 {
+        match self {
+            // wallet v5 mixes the network, the workchain and a 15-bit subwallet number into the id,
+            // see wallet-contract-v5
+            WalletKind::V5R1 => global_id ^ (((1u32 << 31) | (((workchain as u8) as u32) << 23) | (subwallet & 0x7fff)) as i32),
+            _ => DEFAULT_WALLET_ID.wrapping_add(subwallet as i32),
+        }
+    }
+
+    /// Highest subwallet number the contract can encode
+    pub fn max_subwallet(&self) -> u32 {
+        match self {
+            WalletKind::V5R1 => 0x7fff,
+            _ => u32::MAX,
+        }
+    }
+
+    /// Matching tonlib wallet version, if tonlib ships its code
+    pub fn tonlib_version(&self) -> Option<WalletVersion> {
+        match self {
+            WalletKind::V3R1 => Some(WalletVersion::V3R1),
+            WalletKind::V3R2 => Some(WalletVersion::V3R2),
+            WalletKind::V4R2 => Some(WalletVersion::V4R2),
+            WalletKind::HighloadV1R1 => Some(WalletVersion::HighloadV1R1),
+            WalletKind::HighloadV1R2 => Some(WalletVersion::HighloadV1R2),
+            WalletKind::HighloadV2 => Some(WalletVersion::HighloadV2),
+            WalletKind::HighloadV2R1 => Some(WalletVersion::HighloadV2R1),
+            WalletKind::HighloadV2R2 => Some(WalletVersion::HighloadV2R2),
+            WalletKind::V5R1 => None,
+        }
+    }
+
+    /// Name of the version as used on the command line, e.g. `v4r2`
+    pub fn name(&self) -> &'static str {
+        match self {
+            WalletKind::V3R1 => "v3r1",
+            WalletKind::V3R2 => "v3r2",
+            WalletKind::V4R2 => "v4r2",
+            WalletKind::V5R1 => "v5r1",
+            WalletKind::HighloadV1R1 => "highload-v1r1",
+            WalletKind::HighloadV1R2 => "highload-v1r2",
+            WalletKind::HighloadV2 => "highload-v2",
+            WalletKind::HighloadV2R1 => "highload-v2r1",
+            WalletKind::HighloadV2R2 => "highload-v2r2",
+        }
+    }
+}
+
+impl Display for WalletKind {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for WalletKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        WalletKind::ALL
+            .into_iter()
+            .find(|kind| kind.name().eq_ignore_ascii_case(s))
+            .ok_or_else(|| format!("unknown wallet version {:?}", s))
+    }
+}
+
+/// Generate a new mnemonic together with its key pair, protected by `password` if given
+pub fn generate_key_pair(password: Option<&str>) -> Result<(KeyPair, String)> {
+    generate_key_pair_from(&mut OsRng, password)
+}
+
+/// [`generate_key_pair`] drawing the words from `rng`
+pub fn generate_key_pair_from<R: Rng + CryptoRng>(rng: &mut R, password: Option<&str>) -> Result<(KeyPair, String)> {
+    let mnemonic = generate_mnemonic(rng, password);
+    Ok((mnemonic_to_key_pair(&mnemonic, password)?, mnemonic))
+}
+
+/// Key pair of a raw 32 byte Ed25519 seed, in the layout of tonlib (secret key = seed + public key)
+pub fn key_pair_from_seed(seed: &[u8; 32]) -> KeyPair {
+    let public_key = SigningKey::from_bytes(seed).verifying_key().to_bytes();
+    KeyPair { public_key: public_key.to_vec(), secret_key: [seed.as_slice(), &public_key].concat() }
+}
+
+/// Generate a key pair from a random Ed25519 seed, without a mnemonic
+pub fn generate_raw_key_pair() -> KeyPair {
+    generate_raw_key_pair_from(&mut OsRng)
+}
+
+/// [`generate_raw_key_pair`] drawing the seed from `rng`
+pub fn generate_raw_key_pair_from<R: RngCore + CryptoRng>(rng: &mut R) -> KeyPair {
+    let mut seed = [0u8; 32];
+    rng.fill_bytes(&mut seed);
+    key_pair_from_seed(&seed)
+}
+
+/// Code cell of a wallet version
+fn wallet_code(version: WalletKind) -> Result<ArcCell> {
+    if let Some(version) = version.tonlib_version() {
+        return Ok(version.code()?.clone());
+    }
+    static CODE: OnceLock<ArcCell> = OnceLock::new();
+    match CODE.get() {
+        Some(code) => Ok(code.clone()),
+        None => {
+            let code = BagOfCells::parse_base64(WALLET_V5R1_CODE)?.single_root()?.clone();
+            Ok(CODE.get_or_init(|| code).clone())
+        }
+    }
+}
+
+/// Data cell of a new wallet contract
+fn wallet_data(key_pair: &KeyPair, version: WalletKind, wallet_id: i32) -> Result<ArcCell> {
+    let public_key: [u8; 32] = key_pair.public_key.as_slice().try_into()
+        .map_err(|_| TonCellError::InternalError("Invalid public key size".to_string()))?;
+    // tonlib has the code of every highload wallet but only builds the data of v2r2, the older
+    // revisions keep the same layouts as wallet v3 and highload v2r2
+    let data = match version {
+        WalletKind::V5R1 => CellBuilder::new()
+            .store_bit(true)? // signature allowed
+            .store_u32(32, 0)? // seqno
+            .store_u32(32, wallet_id as u32)? // store_i32 misplaces negative ids after the first bit
+            .store_slice(&public_key)?
+            .store_bit(false)? // empty extensions dict
+            .build()?,
+        WalletKind::HighloadV1R1 | WalletKind::HighloadV1R2 => WalletDataV3 { seqno: 0, wallet_id, public_key }.try_into()?,
+        WalletKind::HighloadV2 | WalletKind::HighloadV2R1 => WalletDataHighloadV2R2 { wallet_id, last_cleaned_time: 0, public_key }.try_into()?,
+        _ => {
+            let version = version.tonlib_version().expect("tonlib builds the data of the other versions");
+            return Ok(version.initial_data(key_pair, wallet_id)?);
+        }
+    };
+    Ok(Arc::new(data))
+}
+
+/// State init of a new wallet contract, sent along with its first external message to deploy it
+pub fn wallet_state_init(key_pair: &KeyPair, version: WalletKind, wallet_id: i32) -> Result<Cell> {
+    Ok(StateInitBuilder::new(&wallet_code(version)?, &wallet_data(key_pair, version, wallet_id)?).build()?)
+}
+
+/// Derive the address of a wallet contract deployed with the given key pair and wallet id
+///
+/// Builds the full state init like tonlib does, [`AddressDeriver`] gets the same address much faster.
+pub fn derive_wallet_address(key_pair: &KeyPair, version: WalletKind, workchain: i32, wallet_id: i32) -> Result<TonAddress> {
+    let hash = StateInit::create_account_id(&wallet_code(version)?, &wallet_data(key_pair, version, wallet_id)?)?;
+    let hash_part: [u8; 32] = hash.as_slice().try_into()
+        .map_err(|_| TonCellError::InternalError("StateInit returned hash of wrong size".to_string()))?;
+    Ok(TonAddress::new(workchain, &hash_part))
+}
+
+/// Wallet of a public key, see [`wallets_of_key`]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct KeyWallet {
+    /// Wallet contract version
+    pub version: WalletKind,
+    /// Subwallet id the wallet id was derived from
+    pub subwallet: u32,
+    /// Wallet id of the contract
+    pub wallet_id: i32,
+    /// Address of the wallet
+    pub address: TonAddress,
+}
+
+/// Wallets of `public_key` for each of `versions` and its first `subwallets` subwallet ids
+pub fn wallets_of_key(public_key: &[u8], versions: &[WalletKind], subwallets: u32, workchain: i32, global_id: i32) -> Result<Vec<KeyWallet>> {
+    if public_key.len() != 32 {
+        return Err(Error::InvalidKey(format!("public key must be 32 bytes, got {}", public_key.len())));
+    }
+    let key_pair = KeyPair { public_key: public_key.to_vec(), secret_key: Vec::new() };
+    let mut wallets = Vec::new();
+    for &version in versions {
+        let deriver = AddressDeriver::new(version)?;
+        for subwallet in 0..subwallets.min(version.max_subwallet().saturating_add(1)) {
+            let wallet_id = version.wallet_id(global_id, workchain, subwallet);
+            let address = deriver.derive(&key_pair, workchain, wallet_id)?;
+            wallets.push(KeyWallet { version, subwallet, wallet_id, address });
+        }
+    }
+    Ok(wallets)
+}
+
+/// Fast address derivation for one wallet version
+///
+/// The account id of a wallet is the hash of its state init cell, which only refers to the hashes of
+/// the code and data cells. The code hash is computed once, so every address costs two SHA-256 of
+/// a few dozen bytes instead of building and hashing cells.
+#[derive(Clone, Debug)]
+pub struct AddressDeriver {
+    version: WalletKind,
+    code_hash: [u8; 32],
+    code_depth: u16,
+}
+
+impl AddressDeriver {
+    /// Precompute the code cell of `version`
+    pub fn new(version: WalletKind) -> Result<Self> {
+        let code = wallet_code(version)?;
+        Ok(AddressDeriver { version, code_hash: code.cell_hash(), code_depth: code.cell_depth() })
+    }
+
+    /// Address of the wallet with the given key pair and wallet id, same as [`derive_wallet_address`]
+    pub fn derive(&self, key_pair: &KeyPair, workchain: i32, wallet_id: i32) -> Result<TonAddress> {
+        let public_key: &[u8; 32] = key_pair.public_key.as_slice().try_into()
+            .map_err(|_| TonCellError::InternalError("Invalid public key size".to_string()))?;
+        let mut data = Bits::new();
+        match self.version {
+            WalletKind::V3R1 | WalletKind::V3R2 | WalletKind::HighloadV1R1 | WalletKind::HighloadV1R2 => {
+                data.push(0, 32); // seqno
+                data.push(wallet_id as u32 as u64, 32);
+                data.push_bytes(public_key);
+            }
+            WalletKind::V4R2 => {
+                data.push(0, 32); // seqno
+                data.push(wallet_id as u32 as u64, 32);
+                data.push_bytes(public_key);
+                data.push(0, 1); // empty plugins dict
+            }
+            WalletKind::V5R1 => {
+                data.push(1, 1); // signature allowed
+                data.push(0, 32); // seqno
+                data.push(wallet_id as u32 as u64, 32);
+                data.push_bytes(public_key);
+                data.push(0, 1); // empty extensions dict
+            }
+            WalletKind::HighloadV2 | WalletKind::HighloadV2R1 | WalletKind::HighloadV2R2 => {
+                data.push(wallet_id as u32 as u64, 32);
+                data.push(0, 64); // last cleaned time
+                data.push_bytes(public_key);
+                data.push(0, 1); // empty queries dict
+            }
+        }
+        let data_hash = data.hash(&[]);
+
+        // no split depth, no special, code and data, no library
+        let mut state_init = Bits::new();
+        state_init.push(0b00110, 5);
+        let hash_part = state_init.hash(&[(self.code_depth, &self.code_hash), (0, &data_hash)]);
+        Ok(TonAddress::new(workchain, &hash_part))
+    }
+}
+
+/// Data bits of an ordinary cell being built
+struct Bits {
+    bytes: [u8; 64],
+    len: usize,
+}
+
+impl Bits {
+    fn new() -> Self {
+        Bits { bytes: [0; 64], len: 0 }
+    }
+
+    /// Append the lowest `count` bits of `value`, most significant first
+    fn push(&mut self, value: u64, count: usize) {
+        for i in (0..count).rev() {
+            if value >> i & 1 == 1 {
+                self.bytes[self.len / 8] |= 0x80 >> (self.len % 8);
+            }
+            self.len += 1;
+        }
+    }
+
+    fn push_bytes(&mut self, bytes: &[u8]) {
+        if self.len.is_multiple_of(8) {
+            self.bytes[self.len / 8..self.len / 8 + bytes.len()].copy_from_slice(bytes);
+            self.len += bytes.len() * 8;
+        } else {
+            bytes.iter().for_each(|&byte| self.push(byte as u64, 8));
+        }
+    }
+
+    /// Representation hash of the cell holding these bits and references to level 0 cells given
+    /// by their depth and hash
+    fn hash(mut self, refs: &[(u16, &[u8; 32])]) -> [u8; 32] {
+        let full_bytes = self.len / 8;
+        let data_len = self.len.div_ceil(8);
+        if !self.len.is_multiple_of(8) {
+            // completion tag
+            self.bytes[full_bytes] |= 0x80 >> (self.len % 8);
+        }
+        let mut hasher = Sha256::new();
+        hasher.update([refs.len() as u8, (full_bytes + data_len) as u8]);
+        hasher.update(&self.bytes[..data_len]);
+        refs.iter().for_each(|(depth, _)| hasher.update(depth.to_be_bytes()));
+        refs.iter().for_each(|(_, hash)| hasher.update(hash));
+        hasher.finalize().into()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+    use tonlib::mnemonic::Mnemonic;
+    use tonlib::wallet::TonWallet;
+    use crate::network::{MAINNET_GLOBAL_ID, TESTNET_GLOBAL_ID};
+
+    #[test]
+    fn wallet_versions_derive_known_addresses() {
+        let key_pair = Mnemonic::from_str("fancy carpet hello mandate penalty trial consider property top vicious exit rebuild tragic profit urban major total month holiday sudden rib gather media vicious", &None)
+            .unwrap()
+            .to_key_pair()
+            .unwrap();
+        let cases = [
+            (WalletKind::V3R1, "EQBiMfDMivebQb052Z6yR3jHrmwNhw1kQ5bcAUOBYsK_VPuK"),
+            (WalletKind::V3R2, "EQA-RswW9QONn88ziVm4UKnwXDEot5km7GEEXsfie_0TFOCO"),
+            (WalletKind::V4R2, "EQCDM_QGggZ3qMa_f3lRPk4_qLDnLTqdi6OkMAV2NB9r5TG3"),
+        ];
+        for (version, expected) in cases {
+            let wallet_id = version.wallet_id(TESTNET_GLOBAL_ID, 0, 0);
+            let address = derive_wallet_address(&key_pair, version, 0, wallet_id).unwrap();
+            assert_eq!(address, TonAddress::from_str(expected).unwrap(), "version: {:?}", version);
+        }
+
+        let key_pair = Mnemonic::from_str("section garden tomato dinner season dice renew length useful spin trade intact use universe what post spike keen mandate behind concert egg doll rug", &None)
+            .unwrap()
+            .to_key_pair()
+            .unwrap();
+        let mainnet_wallet_id = WalletKind::V5R1.wallet_id(MAINNET_GLOBAL_ID, 0, 0);
+        assert_eq!(mainnet_wallet_id, 0x7FFFFF11);
+        let address = derive_wallet_address(&key_pair, WalletKind::V5R1, 0, mainnet_wallet_id).unwrap();
+        assert_eq!(address, TonAddress::from_str("UQDv2YSmlrlLH3hLNOVxC8FcQf4F9eGNs4vb2zKma4txo6i3").unwrap());
+    }
+
+    #[test]
+    fn raw_keys_match_mnemonic_keys() {
+        let key_pair = Mnemonic::from_str("dose ice enrich trigger test dove century still betray gas diet dune use other base gym mad law immense village world example praise game", &None)
+            .unwrap()
+            .to_key_pair()
+            .unwrap();
+        let seed: [u8; 32] = key_pair.secret_key[..32].try_into().unwrap();
+        assert!(key_pair_from_seed(&seed) == key_pair);
+        assert_eq!(generate_raw_key_pair().secret_key.len(), 64);
+    }
+
+    #[test]
+    fn subwallet_ids() {
+        assert_eq!(WalletKind::V4R2.wallet_id(TESTNET_GLOBAL_ID, 0, 0), DEFAULT_WALLET_ID);
+        assert_eq!(WalletKind::V4R2.wallet_id(TESTNET_GLOBAL_ID, 0, 5), DEFAULT_WALLET_ID + 5);
+        assert_eq!(WalletKind::V5R1.wallet_id(MAINNET_GLOBAL_ID, 0, 1), 0x7FFFFF10);
+        assert_eq!(WalletKind::V5R1.wallet_id(TESTNET_GLOBAL_ID, 0, 0), 0x7FFFFFFD);
+    }
+
+    #[test]
+    fn wallet_version_names() {
+        for kind in WalletKind::ALL {
+            assert_eq!(kind.name().parse::<WalletKind>(), Ok(kind));
+        }
+        assert_eq!("HIGHLOAD-V2R2".parse::<WalletKind>(), Ok(WalletKind::HighloadV2R2));
+        assert!("v4r1".parse::<WalletKind>().is_err());
+    }
+
+    #[test]
+    fn every_version_has_its_own_address() {
+        let key_pair = key_pair_from_seed(&[3; 32]);
+        let mut addresses: Vec<TonAddress> = WalletKind::ALL
+            .iter()
+            .map(|&version| derive_wallet_address(&key_pair, version, 0, version.wallet_id(TESTNET_GLOBAL_ID, 0, 0)).unwrap())
+            .collect();
+        addresses.sort_by_key(|address| address.to_hex());
+        addresses.dedup();
+        assert_eq!(addresses.len(), WalletKind::ALL.len());
+    }
+
+    #[test]
+    fn wallets_of_a_key() {
+        let key_pair = Mnemonic::from_str("fancy carpet hello mandate penalty trial consider property top vicious exit rebuild tragic profit urban major total month holiday sudden rib gather media vicious", &None)
+            .unwrap()
+            .to_key_pair()
+            .unwrap();
+        let wallets = wallets_of_key(&key_pair.public_key, &WalletKind::ALL, 3, 0, TESTNET_GLOBAL_ID).unwrap();
+        assert_eq!(wallets.len(), WalletKind::ALL.len() * 3);
+        let v4r2 = wallets.iter().find(|wallet| wallet.version == WalletKind::V4R2 && wallet.subwallet == 0).unwrap();
+        assert_eq!(v4r2.address.to_base64_url(), "EQCDM_QGggZ3qMa_f3lRPk4_qLDnLTqdi6OkMAV2NB9r5TG3");
+        for wallet in &wallets {
+            assert_eq!(wallet.wallet_id, wallet.version.wallet_id(TESTNET_GLOBAL_ID, 0, wallet.subwallet));
+            assert_eq!(wallet.address, derive_wallet_address(&key_pair, wallet.version, 0, wallet.wallet_id).unwrap());
+        }
+        assert!(matches!(wallets_of_key(&[0; 31], &WalletKind::ALL, 1, 0, TESTNET_GLOBAL_ID), Err(Error::InvalidKey(_))));
+    }
+
+    proptest! {
+        #[test]
+        fn fast_derivation_matches_tonwallet(
+            public_key in any::<[u8; 32]>(),
+            wallet_id in any::<i32>(),
+            workchain in -1i32..=0,
+            version in proptest::sample::select(WalletKind::ALL.to_vec()),
+        ) {
+            let key_pair = KeyPair { public_key: public_key.to_vec(), secret_key: Vec::new() };
+            let fast = AddressDeriver::new(version).unwrap().derive(&key_pair, workchain, wallet_id).unwrap();
+            prop_assert_eq!(&fast, &derive_wallet_address(&key_pair, version, workchain, wallet_id).unwrap());
+            // tonlib only builds the data of some versions
+            if let Some(wallet) = version.tonlib_version().and_then(|tonlib| TonWallet::derive(workchain, tonlib, &key_pair, wallet_id).ok()) {
+                prop_assert_eq!(fast, wallet.address);
+            }
+        }
+    }
+}
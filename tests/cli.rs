@@ -0,0 +1,57 @@
+use std::io::Write;
+use std::process::{Command, Output, Stdio};
+
+/// Run the binary offline on an evenly split basechain, feeding `stdin` to it
+fn run(args: &[&str], stdin: &str) -> Output {
+    run_with(args, stdin, &[])
+}
+
+/// Like [`run`], with extra environment variables
+fn run_with(args: &[&str], stdin: &str, env: &[(&str, &str)]) -> Output {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_ton-shard-master"))
+        .envs(env.iter().copied())
+        .args(["--split-depth", "2"])
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child.stdin.take().unwrap().write_all(stdin.as_bytes()).unwrap();
+    child.wait_with_output().unwrap()
+}
+
+#[test]
+fn successful_commands_exit_with_zero() {
+    let output = run(&["--output", "json", "generate", "--prefix", "1", "--key-format", "raw"], "");
+    assert_eq!(output.status.code(), Some(0), "{}", String::from_utf8_lossy(&output.stderr));
+    assert!(String::from_utf8_lossy(&output.stdout).trim_start().starts_with('{'));
+}
+
+#[test]
+fn failed_commands_exit_with_one() {
+    // not a binary prefix
+    assert_eq!(run(&["generate", "--prefix", "12"], "").status.code(), Some(1));
+    // a mnemonic password needs a mnemonic
+    assert_eq!(run(&["generate", "--prefix", "1", "--key-format", "raw", "--mnemonic-password"], "").status.code(), Some(1));
+    // one of the addresses is invalid
+    assert_eq!(run(&["shard", "-"], "EQBvI0aFLnw2QbZgjMPCLRdtRHxhUyinQudg6sdiohIwg5jL\nnot an address\n").status.code(), Some(1));
+    // the password is given, so it is the keystore that cannot be read
+    let output = run_with(&["decrypt", "/nonexistent/keystore.json"], "", &[("TON_SHARD_MASTER_PASSWORD", "secret")]);
+    assert_eq!(output.status.code(), Some(1));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("Failed to decrypt keystore /nonexistent/keystore.json"));
+    // every subwallet id of the key is tried, none lands on a 24 bit prefix
+    let output = run(&["generate", "--subwallet", "--wallet-version", "v5r1", "--prefix", "101010101010101010101010", "--key-format", "raw", "--seed", "1", "--quiet"], "");
+    assert_eq!(output.status.code(), Some(1));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("exhausted at id 32767"));
+    // no terminal to choose a shard on
+    let output = run(&["generate"], "");
+    assert_eq!(output.status.code(), Some(1));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("pass --shard"));
+}
+
+#[test]
+fn usage_errors_exit_with_two() {
+    assert_eq!(run(&["generate", "--count", "2", "--all-shards"], "").status.code(), Some(2));
+    assert_eq!(run(&["--config", "global.config.json", "shards"], "").status.code(), Some(2));
+}